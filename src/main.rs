@@ -1,17 +1,23 @@
 #![allow(clippy::absurd_extreme_comparisons)]
 
 use std::cmp::{max, min};
+use std::rc::Rc;
 
 use gloo::console::log;
+use rand::Rng;
 use web_sys::{wasm_bindgen::{closure::Closure, JsCast, JsValue}, window, CanvasRenderingContext2d, HtmlCanvasElement, ImageBitmap};
 use yew::prelude::*;
-use world_grid::{World};
+use world_grid::{World, GRAVITY};
+use material::{Aging, MaterialId, MaterialRegistry};
 use datatype::*;
 
 mod datatype;
+mod material;
 mod world_grid;
 
 const pointsize : usize = 4;
+// How hard attractors placed from the canvas pull, or push for repellers
+const ATTRACTOR_STRENGTH : f32 = 30.0;
 
 #[derive(Properties)]
 struct SandboxCanvas {
@@ -20,12 +26,19 @@ struct SandboxCanvas {
     height: usize,
     bg_color: (u8,u8,u8),
     mouse_pos: (u32,u32),
+    // Where the mouse was over the world on the last frame, to know which way it's dragged
+    last_world_pos: (usize,usize),
     render_cb: Closure<dyn FnMut()>,
     world: World,
     world_bitmap: Option<ImageBitmap>,
     emitting: bool,
     pointsize: usize,
-    tickcount: usize,
+    materials: Rc<MaterialRegistry>,
+    brush: Brush,
+    // Cells to redraw on the next frame, even if the world didn't change them
+    stale: Vec<Vector2<usize>>,
+    // Gravity to restore when leaving zero-g
+    saved_gravity: Vector2<f32>,
 }
 
 impl PartialEq for SandboxCanvas {
@@ -42,6 +55,37 @@ enum SandboxMsg {
     MouseClickUp(),
     FitCanvas(),
     MouseMovement(MouseEvent),
+    KeyDown(KeyboardEvent),
+}
+
+// What gets painted while the mouse is down
+#[derive(Clone, Copy, PartialEq)]
+enum Brush {
+    Material(MaterialId),
+    Heat,
+    Cool,
+    Eraser,
+    Wind,
+    // Clicking places one, or removes the ones already there
+    Attractor,
+    Repeller,
+}
+
+impl Brush {
+    // Materials pick their own keys in materials.json
+    fn from_key(key: &str, materials: &MaterialRegistry) -> Option<Brush> {
+        match key {
+            "7" => Some(Brush::Heat),
+            "8" => Some(Brush::Cool),
+            "0" => Some(Brush::Eraser),
+            "v" => Some(Brush::Wind),
+            "p" => Some(Brush::Attractor),
+            "x" => Some(Brush::Repeller),
+            _ => materials.iter()
+                .find(|(_, material)| material.brush.as_ref().map_or(false, |brush| brush.key == key))
+                .map(|(id, _)| Brush::Material(id)),
+        }
+    }
 }
 
 
@@ -76,14 +120,38 @@ impl DrawableSurface for CanvasRenderingContext2d {
     }
 }
 
+// Hot glowing particles go from their own color to a flickering bright yellow
+fn glow(color: (u8,u8,u8), temperature: f32) -> (u8,u8,u8) {
+    let heat = ((temperature - 700.0) / 500.0).clamp(0.0, 1.0);
+    let t = heat * rand::thread_rng().gen_range(0.6..=1.0);
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    (lerp(color.0, 255), lerp(color.1, 220), lerp(color.2, 100))
+}
+
+// Aging particles shift from their own color to the one they get once fully aged
+fn aged(color: (u8,u8,u8), aging: &Aging, age: u64) -> (u8,u8,u8) {
+    let t = (age as f32 / aging.ticks as f32).min(1.0);
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    (lerp(color.0, aging.color.0), lerp(color.1, aging.color.1), lerp(color.2, aging.color.2))
+}
+
+// Solvents take on some of the color of what's dissolved in them, the more the more concentrated
+fn tint(color: (u8,u8,u8), solute: (u8,u8,u8), concentration: f32) -> (u8,u8,u8) {
+    let t = concentration.clamp(0.0, 1.0) * 0.6;
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    (lerp(color.0, solute.0), lerp(color.1, solute.1), lerp(color.2, solute.2))
+}
+
 impl SandboxCanvas {
     
     fn resize(&mut self, new_width: usize, new_height: usize) {
         self.width = new_width;
         self.height = new_height;
+        let gravity = self.world.gravity();
         self.world = World::new(
-            new_width/self.pointsize, new_height/self.pointsize
+            new_width/self.pointsize, new_height/self.pointsize, self.materials.clone()
         );
+        self.world.set_gravity(gravity);
     }
     
     fn render (&mut self, new_points: Vec<Vector2<usize>>) {
@@ -105,10 +173,20 @@ impl SandboxCanvas {
                 match self.world.get(point.x, point.y) {
                     world_grid::CellType::Empty =>
                         (255,255,255),
-                    world_grid::CellType::Sand(particle_data) =>
-                        particle_data.color,
-                    _ =>
-                        (0,0,0)
+                    world_grid::CellType::Particle(_, particle_data) if particle_data.charge > 0 =>
+                        (255, 250, 170),
+                    world_grid::CellType::Particle(material, particle_data) => {
+                        let material = self.materials.get(*material);
+                        let color = material.aging.as_ref().map_or(particle_data.color, |aging| aged(particle_data.color, aging, self.world.age(particle_data)));
+                        if material.glows {
+                            glow(color, self.world.temperature(point.x, point.y))
+                        } else if let (true, Some(solution)) = (particle_data.concentration > 0.0, &material.solution) {
+                            let solute = self.materials.id(&solution.solute).map_or((255, 255, 255), |solute| self.materials.get(solute).color[0]);
+                            tint(color, solute, particle_data.concentration)
+                        } else {
+                            color
+                        }
+                    },
                 }
             );
             context.fill_rect(
@@ -120,6 +198,19 @@ impl SandboxCanvas {
             context.fill();
         }
 
+        // attractors get drawn over whatever is in their cell
+        for attractor in self.world.attractors() {
+            context.begin_path();
+            context.update_color(if attractor.strength > 0.0 { (150, 60, 220) } else { (230, 140, 30) });
+            context.fill_rect(
+                pointsize as f64 * attractor.position.x as f64,
+                pointsize as f64 * attractor.position.y as f64,
+                pointsize as f64,
+                pointsize as f64
+            );
+            context.fill();
+        }
+
         // context.update_color((0,244,120));
         // context.arc(
         //     self.mouse_pos.0 as f64, self.mouse_pos.1 as f64,
@@ -169,20 +260,29 @@ impl yew::Component for SandboxCanvas {
 
         resize_closure.forget();
 
+        let materials = Rc::new(MaterialRegistry::builtin());
+        let brush = materials.iter()
+            .find(|(_, material)| material.brush.is_some())
+            .map_or(Brush::Eraser, |(id, _)| Brush::Material(id));
+
         Self {
             canvas: NodeRef::default(),
             width,
             height,
             bg_color: (0,0,0),
             mouse_pos: (120,120),
+            last_world_pos: (0,0),
             render_cb,
             world: World::new(
-                width/pointsize, height/pointsize
+                width/pointsize, height/pointsize, materials.clone()
             ),
             world_bitmap: None,
             emitting: false,
             pointsize,
-            tickcount: 0,
+            materials,
+            brush,
+            stale: vec![],
+            saved_gravity: GRAVITY,
         }
     }
 
@@ -195,10 +295,12 @@ impl yew::Component for SandboxCanvas {
                     overflow: hidden;\
             "
             id="mycanvas"
+            tabindex="0"
                 ref={self.canvas.clone()}
                 onmousedown={ctx.link().callback(|_| {SandboxMsg::MouseClickDown()})}
                 onmouseup={ctx.link().callback(|_| {SandboxMsg::MouseClickUp()})}
                 onmousemove={ctx.link().callback(|event: MouseEvent| {SandboxMsg::MouseMovement(event)})}
+                onkeydown={ctx.link().callback(|event: KeyboardEvent| {SandboxMsg::KeyDown(event)})}
             >
             </canvas>
         }
@@ -226,24 +328,76 @@ impl yew::Component for SandboxCanvas {
                 let world_x = (ratiox * self.world.width() as f64) as usize;
                 let world_y = (ratioy * self.world.height() as f64) as usize;
                 if (self.emitting) {
-                    self.tickcount += 1;
-                    self.world.add_sand(
-                        world_x,
-                        world_y,
-                        if (self.tickcount%512) <= 256 {
-                            ((self.tickcount%256) as u8, 0 , 0 )
-                        } else {
-                            ( 255 - (self.tickcount%256) as u8, 0 , 0 )
+                    match self.brush {
+                        Brush::Material(material) => {
+                            let radius = self.materials.get(material).brush.as_ref().map_or(1, |brush| brush.radius);
+                            // thin brushes, like rope, draw unbroken lines however fast the mouse goes
+                            let (last_x, last_y) = self.last_world_pos;
+                            let steps = if radius == 0 {
+                                world_x.abs_diff(last_x).max(world_y.abs_diff(last_y))
+                            } else {
+                                0
+                            };
+                            for step in 0..=steps {
+                                let t = if steps == 0 { 1.0 } else { step as f64 / steps as f64 };
+                                self.world.paint(
+                                    (last_x as f64 + (world_x as f64 - last_x as f64) * t).round() as usize,
+                                    (last_y as f64 + (world_y as f64 - last_y as f64) * t).round() as usize,
+                                    material,
+                                    radius
+                                );
+                            }
                         },
-                        8
-                    );
+                        Brush::Heat => self.world.heat(
+                            world_x,
+                            world_y,
+                            6,
+                            25.0
+                        ),
+                        Brush::Cool => self.world.heat(
+                            world_x,
+                            world_y,
+                            6,
+                            -25.0
+                        ),
+                        Brush::Eraser => self.world.erase(
+                            world_x,
+                            world_y,
+                            6
+                        ),
+                        // blows the way the mouse is dragged
+                        Brush::Wind => self.world.blow(
+                            world_x,
+                            world_y,
+                            10,
+                            Vector2{
+                                x: world_x as f32 - self.last_world_pos.0 as f32,
+                                y: world_y as f32 - self.last_world_pos.1 as f32,
+                            }
+                        ),
+                        // placed on click
+                        Brush::Attractor | Brush::Repeller => {},
+                    }
                 }
-                let result = self.world.process_frame();
+                self.last_world_pos = (world_x, world_y);
+                let mut result = self.world.process_frame();
+                result.append(&mut self.stale);
                 self.render( result );
                 false
             },
             SandboxMsg::MouseClickDown() => {
                 self.emitting = true;
+                let strength = match self.brush {
+                    Brush::Attractor => ATTRACTOR_STRENGTH,
+                    Brush::Repeller => -ATTRACTOR_STRENGTH,
+                    _ => return false,
+                };
+                let (x, y) = self.last_world_pos;
+                let mut removed = self.world.remove_attractors(x, y, 2);
+                if removed.is_empty() {
+                    self.world.add_attractor(x, y, strength);
+                }
+                self.stale.append(&mut removed);
                 false
             },
             SandboxMsg::MouseClickUp() => {
@@ -261,6 +415,27 @@ impl yew::Component for SandboxCanvas {
             SandboxMsg::MouseMovement(event) => {
                 self.mouse_pos = (event.client_x() as u32, event.client_y() as u32);
                 false
+            },
+            SandboxMsg::KeyDown(event) => {
+                match event.key().as_str() {
+                    "[" => self.world.set_lifetime_scale(self.world.lifetime_scale()*2.0/3.0),
+                    "]" => self.world.set_lifetime_scale(self.world.lifetime_scale()*3.0/2.0),
+                    // gravity keeps its strength, pointing the way of the arrow
+                    "ArrowDown" => self.world.set_gravity(Vector2{x: 0.0, y: GRAVITY.y}),
+                    "ArrowUp" => self.world.set_gravity(Vector2{x: 0.0, y: -GRAVITY.y}),
+                    "ArrowLeft" => self.world.set_gravity(Vector2{x: -GRAVITY.y, y: 0.0}),
+                    "ArrowRight" => self.world.set_gravity(Vector2{x: GRAVITY.y, y: 0.0}),
+                    "z" => if self.world.gravity() == (Vector2{x: 0.0, y: 0.0}) {
+                        self.world.set_gravity(self.saved_gravity);
+                    } else {
+                        self.saved_gravity = self.world.gravity();
+                        self.world.set_gravity(Vector2{x: 0.0, y: 0.0});
+                    },
+                    key => if let Some(brush) = Brush::from_key(key, &self.materials) {
+                        self.brush = brush;
+                    },
+                }
+                false
             }
         }
     }
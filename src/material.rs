@@ -0,0 +1,429 @@
+// This is material module
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::world_grid::{CellType, ROPE_LINK};
+
+
+pub type MaterialId = usize;
+
+// How particles of a material move around the grid
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Movement {
+    Static,
+    Powder,
+    Liquid,
+    Gas,
+}
+
+// The material turns into another one once its temperature crosses this threshold
+#[derive(Clone, Deserialize)]
+pub struct PhaseChange {
+    pub temperature: f32,
+    pub into: String,
+}
+
+// One of the possible leftovers when a particle burns out or decays
+#[derive(Clone, Deserialize)]
+pub struct Outcome {
+    pub into: String,
+    pub chance: f64,
+}
+
+// Touching the `with` material may turn both particles into something else.
+// A missing `into` / `other_into` leaves the cell empty, unless `other_stays` leaves the touching particle just as it was
+#[derive(Clone, Deserialize)]
+pub struct Reaction {
+    pub with: String,
+    // Chance per tick and per touching particle
+    pub chance: f64,
+    #[serde(default)]
+    pub into: Option<String>,
+    #[serde(default)]
+    pub other_into: Option<String>,
+    #[serde(default)]
+    pub other_stays: bool,
+}
+
+// Eats away touching particles, getting used up as it does so
+#[derive(Clone, Deserialize)]
+pub struct Corrosion {
+    // Chance per tick of eating a touching particle, while at full strength
+    pub chance: f64,
+    // How many particles it eats before being used up
+    pub strength: u32,
+}
+
+// Drinks touching particles to gather energy, which it spends growing new particles upwards
+#[derive(Clone, Deserialize)]
+pub struct Growth {
+    // What it grows into, and turns into itself once it starts growing
+    pub into: String,
+    pub drinks: String,
+    // Chance per tick of drinking, and of growing while it has energy left
+    pub chance: f64,
+    // Energy gathered with each drink. Every new particle grown spends one
+    pub energy: u32,
+    // Chance of splitting in two when growing
+    pub branching: f64,
+}
+
+// Dissolves touching particles of the solute, carrying them as a concentration, from 0 (pure) to 1 (saturated).
+// Whatever can't stay dissolved when it changes phase crystallizes back out
+#[derive(Clone, Deserialize)]
+pub struct Solution {
+    pub solute: String,
+    // Chance per tick of dissolving a touching solute particle
+    pub chance: f64,
+    // Concentration each dissolved particle adds
+    pub per_particle: f32,
+    // Extra density, and how many degrees lower it freezes, once saturated
+    pub density: f32,
+    pub freezing_drop: f32,
+}
+
+// Blows up when touching flames or getting hotter than `temperature`
+#[derive(Clone, Deserialize)]
+pub struct Explosion {
+    pub temperature: f32,
+    // Everything closer than this is destroyed, and movable particles up to twice as far are flung away
+    pub radius: usize,
+    // Speed given to the particles flung at the edge of the blast
+    pub power: f32,
+}
+
+// Connected particles of the material move together as a single rigid body
+#[derive(Clone, Deserialize)]
+pub struct Rigid {
+    // Impact speed, in cells per tick, from which the body breaks apart
+    pub shatter_speed: f32,
+    // What every particle of the body breaks into
+    pub debris: String,
+}
+
+// Particles of the material get tied to their neighbors, hanging and swinging as a rope from the static particles they touch
+#[derive(Clone, Deserialize)]
+pub struct Rope {
+    // Distance, in cells, from which a link between two particles snaps
+    pub snap_length: f32,
+}
+
+// What a device does on every tick it's powered by a charged conductor
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "kind")]
+pub enum Device {
+    // Warms itself up by this many degrees
+    Heater { heat: f32 },
+    // Spits flames into an empty neighbor cell
+    Igniter,
+    // Spits particles of the named material into an empty neighbor cell
+    Emitter { emits: String },
+}
+
+// Shifts the color of a particle as it gets older, reaching `color` once it's `ticks` old
+#[derive(Clone, Deserialize)]
+pub struct Aging {
+    pub color: (u8,u8,u8),
+    pub ticks: u32,
+}
+
+// How the material is painted from the canvas
+#[derive(Clone, Deserialize)]
+pub struct Brush {
+    pub key: String,
+    pub radius: usize,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Material {
+    pub name: String,
+    // Freshly spawned particles get a random color between these two
+    pub color: [(u8,u8,u8); 2],
+    pub movement: Movement,
+    // Heavier movable particles sink through lighter ones
+    pub density: f32,
+    // How easily heat flows through it, from 0 (perfect insulator) to 1
+    pub conductivity: f32,
+    // Chance per tick of catching fire while touching flames
+    #[serde(default)]
+    pub flammability: f64,
+    // How long it burns before being consumed, as a (min, max) range of ticks
+    #[serde(default = "default_burn_ticks")]
+    pub burn_ticks: (u32, u32),
+    #[serde(default)]
+    pub burns_into: Vec<Outcome>,
+    // Flames set flammable neighbors on fire and keep themselves at their initial temperature
+    #[serde(default)]
+    pub flame: bool,
+    // Sets flammable neighbors on fire, even without being a flame
+    #[serde(default)]
+    pub ignites: bool,
+    // Chance per tick that a resting particle holds still instead of sliding or spreading
+    #[serde(default)]
+    pub viscosity: f64,
+    #[serde(default)]
+    pub corrosion: Option<Corrosion>,
+    // Corrosive materials can't eat it
+    #[serde(default)]
+    pub acid_proof: bool,
+    #[serde(default)]
+    pub growth: Option<Growth>,
+    #[serde(default)]
+    pub solution: Option<Solution>,
+    #[serde(default)]
+    pub explosion: Option<Explosion>,
+    // Carries electric pulses from cell to cell
+    #[serde(default)]
+    pub conducts: bool,
+    // Sends a pulse to touching conductors every this many ticks
+    #[serde(default)]
+    pub power: Option<u32>,
+    #[serde(default)]
+    pub device: Option<Device>,
+    #[serde(default)]
+    pub rigid: Option<Rigid>,
+    #[serde(default)]
+    pub rope: Option<Rope>,
+    // Chance per tick that a settled powder particle holds its slope instead of sliding. Any cohesion at all
+    // makes it hang on to its own kind instead of falling
+    #[serde(default)]
+    pub cohesion: f64,
+    // Share of its speed a particle keeps when bouncing off an obstacle, from 0 (no bounce at all) to 1
+    #[serde(default)]
+    pub restitution: f32,
+    // Share of its sideways speed a particle loses per tick while resting on something, from 0 (slides away) to 1 (stops dead)
+    #[serde(default = "default_friction")]
+    pub friction: f32,
+    // Shines brighter the hotter it is
+    #[serde(default)]
+    pub glows: bool,
+    // Puts out flames it touches
+    #[serde(default)]
+    pub extinguishes: bool,
+    // Average ticks a particle lasts before decaying. None lives forever
+    #[serde(default)]
+    pub lifetime: Option<u32>,
+    #[serde(default)]
+    pub decays_into: Vec<Outcome>,
+    #[serde(default)]
+    pub aging: Option<Aging>,
+    // Temperature freshly spawned particles start at, if not the one of their surroundings
+    #[serde(default)]
+    pub initial_temperature: Option<f32>,
+    #[serde(default)]
+    pub colder_phase: Option<PhaseChange>,
+    #[serde(default)]
+    pub hotter_phase: Option<PhaseChange>,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    #[serde(default)]
+    pub brush: Option<Brush>,
+}
+
+fn default_burn_ticks() -> (u32, u32) {
+    (60, 120)
+}
+
+fn default_friction() -> f32 {
+    0.5
+}
+
+pub struct MaterialRegistry {
+    materials: Vec<Material>,
+    ids: HashMap<String, MaterialId>,
+    flame: Option<MaterialId>,
+}
+
+impl MaterialRegistry {
+
+    // The materials shipped in materials.json
+    pub fn builtin() -> MaterialRegistry {
+        match MaterialRegistry::from_json(include_str!("../materials.json")) {
+            Ok(registry) => registry,
+            Err(error) => panic!("materials.json is invalid: {}", error),
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<MaterialRegistry, String> {
+        let materials : Vec<Material> = serde_json::from_str(json).map_err(|error| error.to_string())?;
+        let mut ids = HashMap::new();
+        for (id, material) in materials.iter().enumerate() {
+            if ids.insert(material.name.clone(), id).is_some() {
+                return Err(format!("material '{}' is defined twice", material.name));
+            }
+        }
+        let flame = materials.iter().position(|material| material.flame);
+        let ret = MaterialRegistry { materials, ids, flame };
+        ret.validate()?;
+        return Ok(ret);
+    }
+
+    // Every material referenced by name must exist
+    fn validate(&self) -> Result<(), String> {
+        for material in self.materials.iter() {
+            let phases = material.colder_phase.iter().chain(material.hotter_phase.iter()).map(|phase| &phase.into);
+            let outcomes = material.burns_into.iter().chain(material.decays_into.iter()).map(|outcome| &outcome.into);
+            let growth = material.growth.iter().flat_map(|growth| [&growth.into, &growth.drinks]);
+            let emits = match &material.device {
+                Some(Device::Emitter { emits }) => Some(emits),
+                _ => None,
+            };
+            let debris = material.rigid.iter().map(|rigid| &rigid.debris);
+            let solute = material.solution.iter().map(|solution| &solution.solute);
+            let reactions = material.reactions.iter().flat_map(|reaction| {
+                std::iter::once(&reaction.with).chain(reaction.into.iter()).chain(reaction.other_into.iter())
+            });
+            for name in phases.chain(outcomes).chain(reactions).chain(growth).chain(emits).chain(debris).chain(solute) {
+                if self.id(name).is_none() {
+                    return Err(format!("material '{}' refers to unknown material '{}'", material.name, name));
+                }
+            }
+            if material.burn_ticks.0 > material.burn_ticks.1 {
+                return Err(format!("material '{}' has an empty burn_ticks range", material.name));
+            }
+            if !(0.0..=1.0).contains(&material.conductivity) {
+                return Err(format!("material '{}' has a conductivity outside of [0, 1]", material.name));
+            }
+            if !(0.0..=1.0).contains(&material.flammability) {
+                return Err(format!("material '{}' has a flammability outside of [0, 1]", material.name));
+            }
+            if !(0.0..=1.0).contains(&material.viscosity) {
+                return Err(format!("material '{}' has a viscosity outside of [0, 1]", material.name));
+            }
+            if !(0.0..=1.0).contains(&material.cohesion) {
+                return Err(format!("material '{}' has a cohesion outside of [0, 1]", material.name));
+            }
+            if !(0.0..=1.0).contains(&material.restitution) {
+                return Err(format!("material '{}' has a restitution outside of [0, 1]", material.name));
+            }
+            if !(0.0..=1.0).contains(&material.friction) {
+                return Err(format!("material '{}' has a friction outside of [0, 1]", material.name));
+            }
+            if let Some(corrosion) = &material.corrosion {
+                if !(0.0..=1.0).contains(&corrosion.chance) || corrosion.strength == 0 {
+                    return Err(format!("material '{}' has an invalid corrosion", material.name));
+                }
+            }
+            if let Some(growth) = &material.growth {
+                if !(0.0..=1.0).contains(&growth.chance) || !(0.0..=1.0).contains(&growth.branching) {
+                    return Err(format!("material '{}' has a growth chance outside of [0, 1]", material.name));
+                }
+            }
+            if let Some(solution) = &material.solution {
+                if material.movement != Movement::Liquid || !(0.0..=1.0).contains(&solution.chance)
+                    || solution.per_particle <= 0.0 || solution.per_particle > 1.0 {
+                    return Err(format!("material '{}' is a solution but not liquid, or has an invalid chance or per_particle", material.name));
+                }
+            }
+            if let Some(rigid) = &material.rigid {
+                if material.movement != Movement::Static || rigid.shatter_speed <= 0.0 {
+                    return Err(format!("material '{}' is rigid but not static, or shatters at no speed", material.name));
+                }
+            }
+            if let Some(rope) = &material.rope {
+                if material.movement != Movement::Static || material.rigid.is_some() || rope.snap_length <= ROPE_LINK {
+                    return Err(format!("material '{}' is a rope but not static, rigid, or snaps before its links even stretch", material.name));
+                }
+            }
+            if material.aging.as_ref().map_or(false, |aging| aging.ticks == 0) {
+                return Err(format!("material '{}' ages over 0 ticks", material.name));
+            }
+            if material.power == Some(0) {
+                return Err(format!("material '{}' has a power period of 0 ticks", material.name));
+            }
+            if material.reactions.iter().any(|reaction| reaction.other_stays && reaction.other_into.is_some()) {
+                return Err(format!("material '{}' has a reaction both keeping and replacing the other particle", material.name));
+            }
+            if material.reactions.iter().any(|reaction| !(0.0..=1.0).contains(&reaction.chance)) {
+                return Err(format!("material '{}' has a reaction chance outside of [0, 1]", material.name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: MaterialId) -> &Material {
+        &self.materials[id]
+    }
+
+    pub fn id(&self, name: &str) -> Option<MaterialId> {
+        self.ids.get(name).copied()
+    }
+
+    // Material of the particle in the cell, if there's any
+    pub fn of(&self, cell: &CellType) -> Option<&Material> {
+        cell.material().map(|id| self.get(id))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (MaterialId, &Material)> {
+        self.materials.iter().enumerate()
+    }
+
+    // The material burning particles spit out as flames
+    pub fn flame(&self) -> Option<MaterialId> {
+        self.flame
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A minimal powder definition, with `extra` fields appended
+    fn material(name: &str, extra: &str) -> String {
+        format!(r#"{{ "name": "{}", "color": [[0, 0, 0], [0, 0, 0]], "movement": "powder", "density": 1.0, "conductivity": 0.5 {} }}"#, name, extra)
+    }
+
+    fn error(materials: &[String]) -> String {
+        MaterialRegistry::from_json(&format!("[{}]", materials.join(","))).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn builtin_materials_are_valid() {
+        assert!(MaterialRegistry::from_json(include_str!("../materials.json")).is_ok());
+    }
+
+    #[test]
+    fn accepts_valid_materials() {
+        let materials = [material("sand", r#", "flammability": 1.0, "hotter_phase": { "temperature": 1000.0, "into": "glass" }"#), material("glass", "")];
+        assert_eq!(error(&materials), "");
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        assert!(error(&[material("sand", ""), material("sand", "")]).contains("defined twice"));
+    }
+
+    #[test]
+    fn rejects_unknown_references() {
+        let unknown = [
+            r#", "hotter_phase": { "temperature": 100.0, "into": "steam" }"#,
+            r#", "burns_into": [{ "into": "steam", "chance": 1.0 }]"#,
+            r#", "reactions": [{ "with": "steam", "chance": 1.0 }]"#,
+            r#", "reactions": [{ "with": "sand", "chance": 1.0, "other_into": "steam" }]"#,
+        ];
+        for extra in unknown {
+            assert!(error(&[material("sand", extra)]).contains("unknown material 'steam'"), "{}", extra);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        let out_of_range = [
+            (r#", "flammability": 1.5"#, "flammability"),
+            (r#", "flammability": -0.1"#, "flammability"),
+            (r#", "viscosity": 2.0"#, "viscosity"),
+            (r#", "cohesion": -1.0"#, "cohesion"),
+            (r#", "restitution": 1.1"#, "restitution"),
+            (r#", "friction": 1.1"#, "friction"),
+            (r#", "reactions": [{ "with": "sand", "chance": 2.0 }]"#, "reaction chance"),
+        ];
+        for (extra, field) in out_of_range {
+            assert!(error(&[material("sand", extra)]).contains(field), "{}", extra);
+        }
+        let conductive = material("sand", "").replace(r#""conductivity": 0.5"#, r#""conductivity": 1.5"#);
+        assert!(error(&[conductive]).contains("conductivity"));
+    }
+}
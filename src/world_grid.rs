@@ -3,7 +3,8 @@ use std::cell::{Cell, RefCell};
 use std::cmp::*;
 use std::ops::{Div, RangeInclusive};
 use std::usize;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 use rand::rngs::ThreadRng;
 use rand::seq::SliceRandom;
@@ -11,38 +12,281 @@ use rand::{thread_rng, Rng};
 use gloo::console::log;
 
 use crate::datatype::{GridMap, Vector2};
+use crate::material::{Device, MaterialId, MaterialRegistry, Movement, Outcome};
 
 
 #[derive(Clone, PartialEq)]
 pub struct ParticleData {
     pub speed: Vector2<f32>,
     pub color: (u8,u8,u8),
+    // Tick the world was at when the particle was made, so its age keeps counting even while its room sleeps
+    pub born: u64,
+    // Ticks left before the particle fades away. None lives forever
+    pub lifetime: Option<u32>,
+    // Ticks left before a burning particle is consumed. None when not on fire
+    pub burning: Option<u32>,
+    // Particles a corrosive particle can still eat. None when not corrosive
+    pub strength: Option<u32>,
+    // Energy a growing particle has left to spend on new growth
+    pub energy: u32,
+    // Electric pulse going through a conductor: CHARGED while carrying it, then counting down to 0 while it recovers
+    pub charge: u8,
+    // Rigid body the particle is part of, if any
+    pub body: Option<u32>,
+    // How much of its solute a solvent particle carries, from 0 (pure) to 1 (saturated)
+    pub concentration: f32,
+    // Rope knot the particle is, if any, and the knots it's linked to
+    pub knot: Option<u32>,
+    pub links: Vec<u32>,
+}
+
+impl ParticleData {
+    pub fn new(speed: Vector2<f32>, color: (u8,u8,u8)) -> ParticleData {
+        ParticleData {
+            speed: speed,
+            color: color,
+            born: 0,
+            lifetime: None,
+            burning: None,
+            strength: None,
+            energy: 0,
+            charge: 0,
+            body: None,
+            concentration: 0.0,
+            knot: None,
+            links: vec![],
+        }
+    }
+}
+
+// A connected group of rigid particles, moving and turning as a single object
+struct RigidBody {
+    id: u32,
+    // Every particle of the body, and where it sits from the center of the body before turning it
+    cells: Vec<(Vector2<f32>, CellType)>,
+    position: Vector2<f32>,
+    velocity: Vector2<f32>,
+    // Clockwise turn, in radians, and how much it turns every tick
+    angle: f32,
+    spin: f32,
+    // Where each of the cells got written into the grid. None when another cell of the body already took its spot
+    placed: Vec<Option<Vector2<usize>>>,
+}
+
+// A rope particle, swinging between cells. Its speed is how far it went since the previous tick
+#[derive(Clone, Copy)]
+struct Knot {
+    position: Vector2<f32>,
+    previous: Vector2<f32>,
+    // Cell of the grid the particle is in
+    cell: Vector2<usize>,
+    // Static particle it's tied to, holding it in place
+    anchor: Option<Vector2<usize>>,
+}
+
+// Where a particle got to when following its speed, and what stopped it
+struct Trace {
+    end: Vector2<usize>,
+    hit_x: bool,
+    hit_y: bool,
+    // Went down into a lighter particle, swapping places with it
+    sinking: bool,
 }
 
 pub struct World {
     grid_rooms_hotness: GridMap<usize>,
+    // Air velocity over each room, in cells per tick
+    wind: GridMap<Vector2<f32>>,
     room_size: Vector2<usize>,
     grid: GridMap<CellType>,
+    temperature: GridMap<f32>,
+    // How hard the rest of its liquid body presses on each liquid cell, in cells of liquid
+    pressure: GridMap<f32>,
+    materials: Rc<MaterialRegistry>,
     rng: ThreadRng,
+    lifetime_scale: f32,
+    // Ticks since the world was created
+    tick: u64,
+    // Pull every particle gets each tick, in cells per tick per tick
+    gravity: Vector2<f32>,
+    attractors: Vec<Attractor>,
+    bodies: Vec<RigidBody>,
+    // Rigid particles set since the last tick, still to be gathered into bodies
+    unbound: Vec<Vector2<usize>>,
+    next_body: u32,
+    knots: HashMap<u32, Knot>,
+    // Rope particles set since the last tick, still to be tied to their neighbors
+    untied: Vec<Vector2<usize>>,
+    next_knot: u32,
+    // Conductors reached by a pulse this tick, charged once every room is done so the pulse travels a single cell per tick
+    charging: Vec<Vector2<usize>>,
+}
+
+// A point pulling particles towards it, or pushing them away with a negative strength.
+// The pull weakens with the square of the distance
+#[derive(Clone, Copy)]
+pub struct Attractor {
+    pub position: Vector2<usize>,
+    pub strength: f32,
 }
 
 
 #[derive(Clone, PartialEq)]
 pub enum CellType {
     Empty,
-    Sand(ParticleData),
-    Block(ParticleData),
+    Particle(MaterialId, ParticleData),
+}
+
+// How many cells a liquid particle may travel sideways in a single tick
+const LIQUID_DISPERSION : usize = 5;
+// Charge of a conductor particle carrying a pulse. It takes a couple more ticks to recover, so pulses can't bounce back
+pub const CHARGED : u8 = 3;
+
+// Pressure lost at every cell it travels sideways, so pressure from liquid that's gone dies out
+const PRESSURE_SIDE_LOSS : f32 = 0.05;
+// Liquid surfaces pressed harder than this get pushed up by the rest of their body
+const PRESSURE_THRESHOLD : f32 = 2.0;
+// How many cells of a liquid body are searched for the surface pressing on another one
+const MAX_PRESSURE_SEARCH : usize = 4096;
+
+// Fastest the air can blow, in cells per tick
+const MAX_WIND : f32 = 4.0;
+// Share of its speed the air keeps every frame, and share it trades with neighbor rooms
+const WIND_DAMPING : f32 = 0.97;
+const WIND_DIFFUSION : f32 = 0.2;
+// Slower air doesn't keep rooms awake
+const WIND_CALM : f32 = 0.1;
+// Only particles lighter than this get carried by the wind, the lighter the more
+const WIND_MAX_DENSITY : f32 = 1.0;
+// How much faster than the air itself the lightest powders get blown
+const WIND_CARRY : f32 = 2.5;
+
+// Default pull of the world, straight down
+pub const GRAVITY : Vector2<f32> = Vector2{x: 0.0, y: 0.15};
+// A single attractor never pulls harder than this, however close particles get
+const MAX_PULL : f32 = 1.0;
+// Particles pulled weaker than this are weightless: nothing is down for them, so they don't slide, spread or rise
+const WEIGHTLESS : f32 = 0.01;
+// The 8 neighbor offsets, turning clockwise from straight up. Directions relative to the pull are picked from here
+const AROUND : [(i32, i32); 8] = [(0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1)];
+
+// Share of its speed a flying particle loses every tick, for each cell per tick it goes. The faster, the harder the air brakes
+const AIR_DRAG : f32 = 0.01;
+// Share of the difference with the wind a gas particle's speed makes up every tick, so a blast still carries it for a while
+const GAS_DRAG : f32 = 0.3;
+// Rigid bodies keep this much of their spin every tick
+const SPIN_DAMPING : f32 = 0.98;
+
+// Share of the concentration difference evened out between neighbor solvent particles every tick.
+// Smaller differences don't keep a room awake
+const MIXING : f32 = 0.25;
+const MIXING_THRESHOLD : f32 = 0.01;
+
+// Rope knots keep this much of their speed every tick
+const ROPE_DAMPING : f32 = 0.98;
+// Farthest linked knots get from each other before pulling back together, in cells
+pub const ROPE_LINK : f32 = 1.0;
+// Passes over the links every tick. The fewer, the more ropes stretch under their load
+const ROPE_ITERATIONS : usize = 24;
+// Particles piled on a knot weigh it down, each one adding this share of its own weight, up to ROPE_MAX_LOAD of them
+const ROPE_LOAD : f32 = 1.0;
+const ROPE_MAX_LOAD : usize = 16;
+
+// Slowest rebound, in cells per tick, that still sends a particle back. Anything slower just stops, so resting particles don't jitter
+const MIN_BOUNCE : f32 = 1.0;
+
+// Temperatures, in celsius
+pub const AMBIENT_TEMPERATURE : f32 = 20.0;
+const BURNING_TEMPERATURE : f32 = 500.0;
+// Empty cells conduct heat like air does
+const AIR_CONDUCTIVITY : f32 = 0.02;
+// Empty and gas cells slowly lose their heat to the surroundings
+const AIR_COOLING : f32 = 0.01;
+// Heat exchanges smaller than this don't keep a room awake
+const HEAT_FLOW_THRESHOLD : f32 = 0.05;
+
+// Which of the AROUND directions a pull points to. None when it's too weak to pull anywhere
+fn heading(pull: Vector2<f32>) -> Option<usize> {
+    if pull.x.abs() < WEIGHTLESS && pull.y.abs() < WEIGHTLESS {
+        return None;
+    }
+    let turns = pull.x.atan2(-pull.y) / std::f32::consts::FRAC_PI_4;
+    Some(turns.round().rem_euclid(8.0) as usize)
+}
+
+// An offset turned clockwise by the angle, in radians
+fn turned(offset: Vector2<f32>, angle: f32) -> Vector2<f32> {
+    let (sin, cos) = angle.sin_cos();
+    Vector2{x: offset.x*cos - offset.y*sin, y: offset.x*sin + offset.y*cos}
+}
+
+// Speed along one axis after hitting an obstacle: reversed and scaled by the restitution,
+// or nothing at all if the rebound is too weak to even move a cell
+fn bounce(speed: f32, restitution: f32) -> f32 {
+    let rebound = -speed * restitution;
+    if rebound.abs() >= MIN_BOUNCE { rebound } else { 0.0 }
+}
+
+impl CellType {
+
+    pub fn material(&self) -> Option<MaterialId> {
+        match self {
+            CellType::Empty => None,
+            CellType::Particle(material, _) => Some(*material),
+        }
+    }
+
+    pub fn particle_data(&self) -> Option<&ParticleData> {
+        match self {
+            CellType::Empty => None,
+            CellType::Particle(_, data) => Some(data),
+        }
+    }
+
+    fn particle_data_mut(&mut self) -> Option<&mut ParticleData> {
+        match self {
+            CellType::Empty => None,
+            CellType::Particle(_, data) => Some(data),
+        }
+    }
+
+    // Same kind of cell, carrying another particle data
+    fn with_data(&self, data: ParticleData) -> CellType {
+        match self {
+            CellType::Empty => CellType::Empty,
+            CellType::Particle(material, _) => CellType::Particle(*material, data),
+        }
+    }
+
+    pub fn is_burning(&self) -> bool {
+        self.particle_data().map_or(false, |data| data.burning.is_some())
+    }
 }
 
 impl World {
 
-    pub fn new(width: usize, height: usize) -> World {
+    pub fn new(width: usize, height: usize, materials: Rc<MaterialRegistry>) -> World {
         let original_grid = GridMap::new(width, height, CellType::Empty);
         let mut ret = World {
             grid: original_grid.clone(),
+            temperature: GridMap::new(width, height, AMBIENT_TEMPERATURE),
+            pressure: GridMap::new(width, height, 0.0),
+            materials: materials,
             grid_rooms_hotness: GridMap::new(24, 24, 0),
+            wind: GridMap::new(24, 24, Vector2{x: 0.0, y: 0.0}),
             room_size: Vector2 { x: 0, y: 0 },
             rng: thread_rng(),
+            lifetime_scale: 1.0,
+            tick: 0,
+            gravity: GRAVITY,
+            attractors: vec![],
+            bodies: vec![],
+            unbound: vec![],
+            next_body: 0,
+            knots: HashMap::new(),
+            untied: vec![],
+            charging: vec![],
+            next_knot: 0,
         };
         ret.room_size = Vector2 {
             x: ret.grid.width().div_ceil(ret.grid_rooms_hotness.width()),
@@ -59,12 +303,39 @@ impl World {
         self.grid.height()
     }
 
+    // Ticks since the particle was made
+    pub fn age(&self, data: &ParticleData) -> u64 {
+        self.tick.saturating_sub(data.born)
+    }
+
+    // Scales how long gases with a lifetime (like smoke and flames) last, compared to their material lifetime
+    pub fn lifetime_scale(&self) -> f32 {
+        self.lifetime_scale
+    }
+
+    pub fn set_lifetime_scale(&mut self, scale: f32) {
+        self.lifetime_scale = scale.max(0.01);
+    }
+
     pub fn get(&self, x:usize, y:usize) -> &CellType {
         self.grid.get(x, y)
     }
 
 
+    pub fn temperature(&self, x:usize, y:usize) -> f32 {
+        *self.temperature.get(x, y)
+    }
+
     pub fn set(&mut self, x: usize, y: usize, cell_type: CellType ) {
+        if let Some(temperature) = self.materials.of(&cell_type).and_then(|material| material.initial_temperature) {
+            self.temperature.set(x, y, temperature);
+        }
+        if self.materials.of(&cell_type).map_or(false, |material| material.rigid.is_some()) {
+            self.unbound.push(Vector2{x: x, y: y});
+        }
+        if self.materials.of(&cell_type).map_or(false, |material| material.rope.is_some()) {
+            self.untied.push(Vector2{x: x, y: y});
+        }
         self.grid.set(x, y, cell_type.clone());
         self.hot(x, y);
     }
@@ -86,7 +357,203 @@ impl World {
         self.rng.gen_range(range)
     }
 
-    pub fn add_sand(&mut self, x: usize, y: usize, color: (u8,u8,u8), radius:usize) {
+    // Paints the material around (x,y): static materials fill the whole disc, the others are scattered over it
+    pub fn paint(&mut self, x: usize, y: usize, material: MaterialId, radius:usize) {
+        if self.materials.get(material).movement == Movement::Static {
+            self.fill(x, y, radius, |world| world.new_particle(material));
+        } else {
+            self.spray(x, y, radius, |world| world.new_particle(material));
+        }
+    }
+
+    // Adds (or takes away, if negative) heat from everything inside the disc around (x,y)
+    pub fn heat(&mut self, x: usize, y: usize, radius:usize, delta: f32) {
+        for cell in self.disc(x, y, radius) {
+            *self.temperature.get_mut(cell.x, cell.y) += delta;
+            self.hot(cell.x, cell.y);
+        }
+    }
+
+    // Sets the air in the rooms around (x,y) blowing with the given velocity
+    pub fn blow(&mut self, x: usize, y: usize, radius:usize, velocity: Vector2<f32>) {
+        let speed = (velocity.x*velocity.x + velocity.y*velocity.y).sqrt();
+        let mut velocity = velocity;
+        if speed > MAX_WIND {
+            velocity.scale(MAX_WIND / speed);
+        }
+        let from = Vector2{x: x.saturating_sub(radius) / self.room_size.x, y: y.saturating_sub(radius) / self.room_size.y};
+        let to = Vector2{
+            x: min((x + radius) / self.room_size.x, self.wind.width() - 1),
+            y: min((y + radius) / self.room_size.y, self.wind.height() - 1),
+        };
+        for room_x in from.x..=to.x {
+            for room_y in from.y..=to.y {
+                self.wind.set(room_x, room_y, velocity);
+                self.grid_rooms_hotness.set_neighbor(room_x as i32, room_y as i32, 12);
+            }
+        }
+    }
+
+    pub fn gravity(&self) -> Vector2<f32> {
+        self.gravity
+    }
+
+    pub fn set_gravity(&mut self, gravity: Vector2<f32>) {
+        self.gravity = gravity;
+        self.wake_all();
+    }
+
+    pub fn attractors(&self) -> &[Attractor] {
+        &self.attractors
+    }
+
+    pub fn add_attractor(&mut self, x: usize, y: usize, strength: f32) {
+        self.attractors.push(Attractor{position: Vector2{x: x, y: y}, strength: strength});
+        self.wake_all();
+    }
+
+    // Removes the attractors within radius of (x,y). Returns where they were
+    pub fn remove_attractors(&mut self, x: usize, y: usize, radius: usize) -> Vec<Vector2<usize>> {
+        let near = |attractor: &Attractor| {
+            let dx = attractor.position.x as i32 - x as i32;
+            let dy = attractor.position.y as i32 - y as i32;
+            dx*dx + dy*dy <= (radius*radius) as i32
+        };
+        let removed = self.attractors.iter().filter(|attractor| near(attractor)).map(|attractor| attractor.position).collect();
+        self.attractors.retain(|attractor| !near(attractor));
+        self.wake_all();
+        removed
+    }
+
+    // Resting particles everywhere have to notice when the pulls change
+    fn wake_all(&mut self) {
+        self.grid_rooms_hotness.iter_mut().for_each(|hotness| *hotness = 12);
+    }
+
+    // Where the particle at (x,y) gets pulled to: the world gravity plus every attractor around
+    fn pull_at(&self, x: usize, y: usize) -> Vector2<f32> {
+        let mut pull = self.gravity;
+        for attractor in self.attractors.iter() {
+            let dx = attractor.position.x as f32 - x as f32;
+            let dy = attractor.position.y as f32 - y as f32;
+            let distance = (dx*dx + dy*dy).sqrt();
+            if distance < 1.0 {
+                continue;
+            }
+            let strength = (attractor.strength / (distance*distance)).clamp(-MAX_PULL, MAX_PULL);
+            pull.x += dx / distance * strength;
+            pull.y += dy / distance * strength;
+        }
+        pull
+    }
+
+    // The cell at (x,y) moved by an offset, if it's inside the world
+    fn offset(&self, x: usize, y: usize, (dx, dy): (i32, i32)) -> Option<Vector2<usize>> {
+        let nx = x as i32 + dx;
+        let ny = y as i32 + dy;
+        if nx < 0 || ny < 0 || nx >= self.width() as i32 || ny >= self.height() as i32 {
+            return None;
+        }
+        Some(Vector2{x: nx as usize, y: ny as usize})
+    }
+
+    // The 8 neighbors of (x,y), in AROUND order, leaving out those past the edge of the world
+    fn neighbors(&self, x: usize, y: usize) -> Vec<Vector2<usize>> {
+        AROUND.iter().filter_map(|&offset| self.offset(x, y, offset)).collect()
+    }
+
+    // Air velocity over the cell at (x,y)
+    fn wind_at(&self, x: usize, y: usize) -> Vector2<f32> {
+        *self.wind.get(x / self.room_size.x, y / self.room_size.y)
+    }
+
+    // Clears everything inside the disc around (x,y)
+    pub fn erase(&mut self, x: usize, y: usize, radius:usize) {
+        for cell in self.disc(x, y, radius) {
+            if *self.get(cell.x, cell.y) != CellType::Empty {
+                self.set(cell.x, cell.y, CellType::Empty);
+            }
+        }
+    }
+
+    fn new_particle(&mut self, material: MaterialId) -> CellType {
+        let materials = self.materials.clone();
+        let material_data = materials.get(material);
+        let speed = match material_data.movement {
+            Movement::Powder | Movement::Liquid => Vector2{x:0.0, y:1.0},
+            Movement::Static | Movement::Gas => Vector2{x:0.0, y:0.0},
+        };
+        // only gases, like smoke and flames, follow the lifetime scale
+        let scale = if material_data.movement == Movement::Gas { self.lifetime_scale } else { 1.0 };
+        CellType::Particle(material, ParticleData{
+            born: self.tick,
+            lifetime: material_data.lifetime.map(|lifetime| self.random_lifetime(lifetime as f32 * scale)),
+            strength: material_data.corrosion.as_ref().map(|corrosion| corrosion.strength),
+            ..ParticleData::new(speed, self.random_color(material))
+        })
+    }
+
+    // Somewhere between the two colors of the material
+    fn random_color(&mut self, material: MaterialId) -> (u8,u8,u8) {
+        let [from, to] = self.materials.get(material).color;
+        let t = self.gen_range(0.0..=1.0);
+        let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        (lerp(from.0, to.0), lerp(from.1, to.1), lerp(from.2, to.2))
+    }
+
+    // Lifetimes vary a bit so a cloud doesn't vanish all at once
+    fn random_lifetime(&mut self, lifetime: f32) -> u32 {
+        let lifetime = lifetime.max(1.0);
+        self.gen_range((lifetime*0.75)..=(lifetime*1.25)).round().max(1.0) as u32
+    }
+
+    // Picks one of the outcomes by their chances, or an empty cell if none of them happens
+    fn random_outcome(&mut self, outcomes: &[Outcome]) -> CellType {
+        let mut roll = self.gen_range(0.0..=1.0);
+        for outcome in outcomes {
+            if roll < outcome.chance {
+                if let Some(material) = self.materials.id(&outcome.into) {
+                    return self.new_particle(material);
+                }
+            }
+            roll -= outcome.chance;
+        }
+        CellType::Empty
+    }
+
+    // Every cell of the disc around (x,y) that lies inside the world
+    fn disc(&self, x: usize, y: usize, radius:usize) -> Vec<Vector2<usize>> {
+        let mut ret = vec![];
+        let radius = radius as i32;
+        for dx in -radius..=radius {
+            for dy in -radius..=radius {
+                let x = x as i32 + dx;
+                let y = y as i32 + dy;
+                if dx*dx + dy*dy > radius*radius || x < 0 || y < 0 {
+                    continue;
+                }
+                if ((x as usize) < self.width()) && ((y as usize) < self.height()) {
+                    ret.push(Vector2{x: x as usize, y: y as usize});
+                }
+            }
+        }
+        ret
+    }
+
+    // Fills the whole disc around (x,y), only over empty cells
+    fn fill<F>(&mut self, x: usize, y: usize, radius:usize, mut new_cell: F)
+    where F : FnMut(&mut Self) -> CellType {
+        for cell in self.disc(x, y, radius) {
+            if *self.get(cell.x, cell.y) == CellType::Empty {
+                let new_cell = new_cell(self);
+                self.set(cell.x, cell.y, new_cell);
+            }
+        }
+    }
+
+    // Scatters new cells around (x,y), only over empty cells
+    fn spray<F>(&mut self, x: usize, y: usize, radius:usize, mut new_cell: F)
+    where F : FnMut(&mut Self) -> CellType {
         let amount = radius*4;
         for _ in 0..amount {
             let real_radius = self.gen_range(0.0..=(radius as f64 + 0.99)).floor() as usize;
@@ -95,10 +562,8 @@ impl World {
             let y = (y as f64 + real_radius as f64 * angle.sin()).floor() as usize;
             if x>=0 && y >= 0 && (x < self.width()) && (y < self.height()) {
                 if self.get(x,y) == &CellType::Empty {
-                    self.set(x, y, CellType::Sand(ParticleData{
-                        speed: Vector2{x:0.0, y:2.0},
-                        color: color,
-                    }));
+                    let cell = new_cell(self);
+                    self.set(x, y, cell);
                 }
             }
         }
@@ -107,6 +572,9 @@ impl World {
     pub fn process_frame(&mut self) -> Vec<Vector2<usize>> {
 
         let mut ret = vec![];
+        self.tick += 1;
+        self.advect_wind();
+        ret.extend(self.integrate_bodies());
         
         self.grid_rooms_hotness.iter_mut().for_each(|x| {
             if *x > 0 { 
@@ -125,10 +593,15 @@ impl World {
                     continue;
                 } else {
                     ret.extend( self.process_room(room_x, room_y ) );
+                    self.conduct_room(room_x, room_y);
+                    self.pressurize_room(room_x, room_y);
                 }
             }   
         }
 
+        ret.extend(self.solve_ropes());
+        ret.extend(self.charge_conductors());
+
         for cell in ret.iter() {
             self.hot(cell.x, cell.y);
         }
@@ -146,145 +619,1826 @@ impl World {
         let height = self.height();
         let width = self.width();
 
-        let mut xvec : Vec<usize> = (
-            (room_x*room_size.x)..min(width, (room_x+1)*room_size.x)
-        ).collect();
-        let mut yvec : Vec<usize> = (
-            (room_y*room_size.y)..min(height-1, (room_y+1)*room_size.y)
-        ).collect();
+        let mut cells : Vec<Vector2<usize>> = ((room_x*room_size.x)..min(width, (room_x+1)*room_size.x))
+            .flat_map(|x| ((room_y*room_size.y)..min(height, (room_y+1)*room_size.y)).map(move |y| Vector2{x: x, y: y}))
+            .collect();
         
+        // the further down along the gravity, the sooner a cell gets processed, so whatever is above can follow right away.
+        // Cells just as deep go in random order
         let mut rng = thread_rng();
-        xvec.shuffle(&mut rng);
-        yvec.shuffle(&mut rng);
-        xvec.sort_unstable();
+        cells.shuffle(&mut rng);
+        let gravity = self.gravity;
+        let depth = |cell: &Vector2<usize>| cell.x as f32 * gravity.x + cell.y as f32 * gravity.y;
+        cells.sort_by(|a, b| depth(b).total_cmp(&depth(a)));
         
+        let materials = self.materials.clone();
         let mut dirty_cells = HashSet::<Vector2<usize>>::new();
 
-        for x in xvec.iter() {
-            for y in yvec.iter().rev() {
-                let x = *x;
-                let y = *y;
-                if dirty_cells.contains(&Vector2{x: x, y: y}) {
+        for position in cells.iter() {
+            let x = position.x;
+            let y = position.y;
+            if dirty_cells.contains(&Vector2{x: x, y: y}) {
+                continue;
+            }
+            if self.should_detonate(x, y) {
+                ret.extend(self.detonate(x, y, &mut dirty_cells));
+                continue;
+            }
+            if self.get(x, y).is_burning() {
+                let (changed, consumed) = self.burn(x, y, &mut dirty_cells);
+                ret.extend(changed);
+                if consumed {
                     continue;
                 }
-                match self.get(x, y) {
-                    CellType::Empty => {},
-                    CellType::Sand(data) => {
-
-                        let mut new_pos : Option<Vector2<usize>> = None;
-                        let mut new_data = Option::<ParticleData>::None;
-                        let data = ParticleData{
-                            speed: Vector2 {
-                                x: data.speed.x,
-                                y: data.speed.y + 0.15,
-                            },
-                            color: data.color,
-                        };
-                        let min_dy = 1;
-                        let max_dy = if self.gen_bool(0.5) {
-                            data.speed.y.floor() as usize
+            }
+            let changed = self.change_phase(x, y, &mut dirty_cells);
+            if !changed.is_empty() {
+                ret.extend(changed);
+                continue;
+            }
+            let reacted = self.react(x, y, &mut dirty_cells);
+            if !reacted.is_empty() {
+                ret.extend(reacted);
+                continue;
+            }
+            ret.extend(self.dissolve(x, y, &mut dirty_cells));
+            ret.extend(self.electrify(x, y, &mut dirty_cells));
+            let (corroded, used_up) = self.corrode(x, y, &mut dirty_cells);
+            ret.extend(corroded);
+            if used_up {
+                continue;
+            }
+            let grown = self.grow(x, y, &mut dirty_cells);
+            if !grown.is_empty() {
+                ret.extend(grown);
+                continue;
+            }
+            if self.is_aging(x, y) {
+                ret.push(Vector2{x: x, y: y});
+            }
+            if self.decay(x, y) {
+                ret.push(Vector2{x: x, y: y});
+                dirty_cells.insert(Vector2{x: x, y: y});
+                continue;
+            }
+            let cell = self.get(x, y).clone();
+            if let Some(material) = materials.of(&cell) {
+                if material.ignites {
+                    ret.extend(self.ignite_neighbors(x, y));
+                }
+                // glowing particles get redrawn every tick
+                if material.glows {
+                    ret.push(Vector2{x: x, y: y});
+                }
+            }
+            match materials.of(&cell).map(|material| material.movement) {
+                None | Some(Movement::Static) => {},
+                Some(movement @ (Movement::Powder | Movement::Liquid)) => {
+
+                    let is_liquid = movement == Movement::Liquid;
+                    let cohesion = materials.of(&cell).unwrap().cohesion;
+                    let pull = self.pull_at(x, y);
+                    let down = heading(pull);
+                    // settled cohesive particles cling to their own kind, even with nothing below them
+                    let clinging = cohesion > 0.0
+                        && cell.particle_data().unwrap().speed == (Vector2{x: 0.0, y: 0.0})
+                        && down.map_or(false, |down| self.clings(x, y, down));
+                    let mut new_pos : Option<Vector2<usize>> = None;
+                    let mut new_data = Option::<ParticleData>::None;
+                    let mut data = cell.particle_data().unwrap().clone();
+                    data.speed.x += pull.x;
+                    data.speed.y += pull.y;
+
+                    // light powders, like dust, get carried by the wind
+                    let density = materials.of(&cell).unwrap().density;
+                    if movement == Movement::Powder && density < WIND_MAX_DENSITY {
+                        let wind = self.wind_at(x, y);
+                        let lightness = WIND_MAX_DENSITY - density;
+                        data.speed.x += (wind.x * lightness * WIND_CARRY - data.speed.x) * 0.5;
+                        data.speed.y += wind.y * lightness * 0.5;
+                    }
+
+                    // Travelling along the speed, cell by cell, up to the first obstacle
+                    let below = down.and_then(|down| self.offset(x, y, AROUND[down]));
+                    if !clinging && self.gen_bool(0.95) {
+                        let path = self.trace(x, y, &cell, data.speed, below, &dirty_cells);
+                        if path.sinking {
+                            // sinking through something lighter, which drags us down to a single cell per tick
+                            new_pos = Some(path.end);
+                            new_data = Some(data.clone());
+                            new_data.as_mut().unwrap().speed = Vector2{x: path.end.x as f32 - x as f32, y: path.end.y as f32 - y as f32};
                         } else {
-                            data.speed.y.ceil() as usize
-                        };
-                        
-                        // Finding the next cell below we can go
-                        if self.gen_bool(0.95) {
-                            for dy in (min_dy..=max_dy) {
-                                // full speed and still empty? nice
-                                if (dy == max_dy) && (y+dy<height) && (*self.get(x, y + dy ) == CellType::Empty) {
-                                    new_pos = Some(Vector2{x: x, y: y+dy});
-                                    new_data = Some(data.clone());
-                                    break;
-                                } 
-                                // hit something, lets check before
-                                else if (y + dy >= height) || (*self.get(x, y + dy ) != CellType::Empty) {
-                                    // there was a before?
-                                    if (dy>1) {
-                                        new_pos = Some(Vector2{x: x.clone(), y: y.clone()+dy-1});
-                                        new_data = Some(ParticleData{
-                                            speed: Vector2{
-                                                x:data.speed.x,
-                                                y:data.speed.y*0.1
-                                            },
-                                            color: data.color
-                                        });
-                                        break;
-                                    } 
-                                    // ugh, maybe we slide
-                                    break
+                            let material = materials.of(&cell).unwrap();
+                            if path.hit_y {
+                                data.speed.y = bounce(data.speed.y, material.restitution);
+                            }
+                            if path.hit_x {
+                                data.speed.x = bounce(data.speed.x, material.restitution);
+                            }
+                            // resting on something rubs sideways speed away, while flying only the air slows it down
+                            let grounded = down.map_or(false, |down| self.offset(path.end.x, path.end.y, AROUND[down])
+                                .map_or(true, |below| !self.passable(&cell, below.x, below.y)));
+                            if grounded {
+                                let along = (data.speed.x*pull.x + data.speed.y*pull.y) / (pull.x*pull.x + pull.y*pull.y);
+                                data.speed.x -= (data.speed.x - pull.x*along) * material.friction;
+                                data.speed.y -= (data.speed.y - pull.y*along) * material.friction;
+                            } else {
+                                let speed = (data.speed.x*data.speed.x + data.speed.y*data.speed.y).sqrt();
+                                data.speed.scale(1.0 - (speed * AIR_DRAG).min(1.0));
+                            }
+                            if path.end != (Vector2{x: x, y: y}) {
+                                new_pos = Some(path.end);
+                                new_data = Some(data.clone());
+                            }
+                        }
+                    }
+
+                    // weightless particles only go where their speed takes them. Others settle along their pull
+                    if let Some(down) = down {
+                        // the neighbor offsets, turning from down
+                        let turn = |turns: i32| AROUND[(down as i32 + turns).rem_euclid(8) as usize];
+                        let below_is_empty = below.map_or(false, |below| self.passable(&cell, below.x, below.y));
+
+                        // pressed liquid surfaces rise, drawing the liquid from the top of the body pressing on them.
+                        // Pressure only builds up under a straight down pull
+                        if new_pos.is_none() && is_liquid && !below_is_empty && AROUND[down] == (0, 1) && y > 0
+                            && *self.get(x, y-1) == CellType::Empty && !dirty_cells.contains(&Vector2{x: x, y: y-1})
+                            && *self.pressure.get(x, y) >= PRESSURE_THRESHOLD {
+                            if let Some(source) = self.pressure_source(x, y) {
+                                if source.y + 1 < y && !dirty_cells.contains(&source) {
+                                    let risen = self.get(source.x, source.y).clone();
+                                    self.move_cell(source, Vector2{x: x, y: y-1}, risen);
+                                    ret.push(source);
+                                    ret.push(Vector2{x: x, y: y-1});
+                                    dirty_cells.insert(source);
+                                    dirty_cells.insert(Vector2{x: x, y: y-1});
+                                    continue;
                                 }
                             }
                         }
+                        // viscous particles are slow to slide and spread, but fall just as fast. Cohesive ones hold steeper slopes
+                        let sluggish = clinging
+                            || self.gen_bool(materials.of(&cell).unwrap().viscosity)
+                            || self.gen_bool(cohesion);
 
-                        let below_is_empty = (y+1 < height) && (*self.get(x, y+1) == CellType::Empty);
-                        
-                        // aggressive slide
-                        if new_pos.is_none() && y > 0 && y < height-1 {
+                        // aggressive slide, when buried and only one side is open
+                        if new_pos.is_none() && !sluggish && !below_is_empty
+                            && self.offset(x, y, turn(4)).map_or(false, |above| *self.get(above.x, above.y) != CellType::Empty) {
                             let rand_dx = self.gen_range(2..=4);
-                            if (x > rand_dx) && (x < width-rand_dx) && !below_is_empty  && *self.get(x,y-1) != CellType::Empty {
-                                let mut fall_right =
-                                    (*self.get(x + rand_dx, y+1) == CellType::Empty) && 
-                                    (*self.get(x - rand_dx, y+1) != CellType::Empty);
-                                let mut fall_left =
-                                    (*self.get(x - rand_dx, y+1) == CellType::Empty) && 
-                                    (*self.get(x + rand_dx, y+1) != CellType::Empty);
-
-                                if fall_right {
-                                    new_pos = Some(Vector2{x:x+rand_dx, y: y+1});
-                                    new_data = Some(data.clone());
-                                    new_data.as_mut().unwrap().speed.y = 1.0;
-                                } else if fall_left  {
-                                    new_pos = Some(Vector2{x:x-rand_dx, y: y+1});
-                                    new_data = Some(data.clone());
-                                    new_data.as_mut().unwrap().speed.y = 1.0;
-                                }
+                            let landings : Vec<Option<Vector2<usize>>> = [turn(-2), turn(2)].iter().map(|&(side_x, side_y)| {
+                                // the way there has to be clear too, no sliding through walls
+                                let clear = (1..rand_dx).all(|d| self.offset(x, y, (side_x*d, side_y*d))
+                                    .map_or(false, |way| self.passable(&cell, way.x, way.y)));
+                                self.offset(x, y, (side_x*rand_dx + AROUND[down].0, side_y*rand_dx + AROUND[down].1))
+                                    .filter(|landing| clear && self.passable(&cell, landing.x, landing.y))
+                            }).collect();
+                            if let [Some(landing), None] | [None, Some(landing)] = landings[..] {
+                                new_pos = Some(landing);
+                                new_data = Some(data.clone());
+                                new_data.as_mut().unwrap().speed = Vector2{x: AROUND[down].0 as f32, y: AROUND[down].1 as f32};
                             }
                         }
 
                         // simple slide
-                        if new_pos.is_none() && (y + 1 < height) && !below_is_empty {
-                            let mut fall_right =
-                                (x < width-1) &&
-                                (*self.get(x + 1, y + 1) == CellType::Empty);
-                            let mut fall_left =
-                                (x > 0) &&
-                                (*self.get(x - 1, y + 1) == CellType::Empty);
-                            if (fall_left && fall_right) {
-                                fall_right = self.gen_bool(0.5);
-                                fall_left = !fall_right;
-                            }
-                            if fall_right {
-                                new_pos = Some(Vector2{x:x+1, y:y+1});
-                                new_data = Some(data.clone());
-                            } else if fall_left  {
-                                new_pos = Some(Vector2{x:x-1, y:y+1});
+                        if new_pos.is_none() && !sluggish && below.is_some() && !below_is_empty {
+                            // squeezing diagonally between two obstacles is cutting a corner too
+                            let slides : Vec<Vector2<usize>> = [(turn(-1), turn(-2)), (turn(1), turn(2))].iter()
+                                .filter(|&&(_, side)| self.offset(x, y, side).map_or(false, |side| self.passable(&cell, side.x, side.y)))
+                                .filter_map(|&(offset, _)| self.offset(x, y, offset))
+                                .filter(|slide| self.passable(&cell, slide.x, slide.y))
+                                .collect();
+                            if let Some(slide) = slides.choose(&mut self.rng) {
+                                new_pos = Some(*slide);
                                 new_data = Some(data.clone());
                             }
                         }
 
-                        
-                        match new_pos {
-                            None => {},
-                            Some(new_pos) => {
-                                if dirty_cells.contains(&new_pos) {
-                                    continue;
-                                } else {
-                                    self.grid.set(x, y, CellType::Empty);
-                                    self.grid.set(new_pos.x, new_pos.y, CellType::Sand(new_data.unwrap()));
-                                    ret.push(Vector2{x: x, y: y});
-                                    ret.push(new_pos);
-                                    dirty_cells.insert(new_pos);
+                        // liquids keep spreading sideways until they find a hole or a level surface
+                        if new_pos.is_none() && !sluggish && is_liquid && !below_is_empty {
+                            let sides = if self.gen_bool(0.5) { [turn(-2), turn(2)] } else { [turn(2), turn(-2)] };
+                            for (side_x, side_y) in sides {
+                                let mut reach = None;
+                                for d in 1..=LIQUID_DISPERSION as i32 {
+                                    let next = match self.offset(x, y, (side_x*d, side_y*d)) {
+                                        Some(next) if self.passable(&cell, next.x, next.y) => next,
+                                        _ => break,
+                                    };
+                                    reach = Some(next);
+                                    // found a hole, no need to go further
+                                    if self.offset(next.x, next.y, AROUND[down]).map_or(false, |hole| self.passable(&cell, hole.x, hole.y)) {
+                                        break;
+                                    }
+                                }
+                                if let Some(reach) = reach {
+                                    new_pos = Some(reach);
+                                    new_data = Some(data.clone());
+                                    break;
                                 }
                             }
                         }
-                    },
-                    CellType::Block(_) => {},
-                }
-            
+                    }
+
+
+                    match new_pos {
+                        None => {
+                            // bounces and friction change the speed even when staying put.
+                            // Cohesive particles remember they settled, so they can cling later on
+                            if let Some(resting) = self.grid.get_mut(x, y).particle_data_mut() {
+                                resting.speed = data.speed;
+                                if cohesion > 0.0 {
+                                    resting.speed = Vector2{x: 0.0, y: 0.0};
+                                }
+                            }
+                        },
+                        Some(new_pos) => {
+                            if dirty_cells.contains(&new_pos) {
+                                continue;
+                            } else {
+                                self.move_cell(Vector2{x: x, y: y}, new_pos, cell.with_data(new_data.unwrap()));
+                                ret.push(Vector2{x: x, y: y});
+                                ret.push(new_pos);
+                                dirty_cells.insert(new_pos);
+                            }
+                        }
+                    }
+                },
+                Some(Movement::Gas) => {
+                    let material = materials.of(&cell).unwrap();
+                    let mut data = cell.particle_data().unwrap().clone();
+                    let wind = self.wind_at(x, y);
+                    let lightness = (WIND_MAX_DENSITY - material.density).max(0.0);
+                    data.speed.x += (wind.x * lightness - data.speed.x) * GAS_DRAG;
+                    data.speed.y += (wind.y * lightness - data.speed.y) * GAS_DRAG;
+                    let expired = data.lifetime.map_or(false, |lifetime| lifetime <= 1);
+                    let doused = material.flame && self.touches(x, y, |neighbor| {
+                        materials.of(neighbor).map_or(false, |neighbor| neighbor.extinguishes)
+                    });
+                    if expired || doused {
+                        let leftover = self.random_outcome(&material.decays_into);
+                        self.set(x, y, leftover);
+                        ret.push(Vector2{x: x, y: y});
+                        continue;
+                    }
+
+                    if let Some(lifetime) = data.lifetime {
+                        data.lifetime = Some(lifetime - 1);
+                        // fading into the background while it still lives
+                        if !material.flame {
+                            let fade = |c: u8| (c as u32 + (255 - c as u32) / lifetime) as u8;
+                            data.color = (fade(data.color.0), fade(data.color.1), fade(data.color.2));
+                        }
+                    }
+                    if material.flame {
+                        data.color = self.random_color(cell.material().unwrap());
+                        ret.extend(self.ignite_neighbors(x, y));
+                    }
+
+                    // fast enough, it's blown along its speed. Otherwise it just drifts up
+                    let mut new_pos = Vector2{x: x, y: y};
+                    if data.speed.x.abs().max(data.speed.y.abs()) >= 1.0 {
+                        let path = self.trace(x, y, &cell, data.speed, None, &dirty_cells);
+                        if path.hit_x {
+                            data.speed.x = bounce(data.speed.x, material.restitution);
+                        }
+                        if path.hit_y {
+                            data.speed.y = bounce(data.speed.y, material.restitution);
+                        }
+                        new_pos = path.end;
+                    }
+                    if new_pos == (Vector2{x: x, y: y}) {
+                        new_pos = self.rise(x, y, data.speed, &dirty_cells);
+                    }
+                    self.move_cell(Vector2{x: x, y: y}, new_pos, cell.with_data(data.clone()));
+                    if let (true, Some(temperature)) = (material.flame, material.initial_temperature) {
+                        self.temperature.set(new_pos.x, new_pos.y, temperature);
+                    }
+                    if data.lifetime.is_some() || material.flame || new_pos != (Vector2{x: x, y: y}) {
+                        ret.push(Vector2{x: x, y: y});
+                    }
+                    // a gas that stayed put can still be sunk into by what's above it
+                    if new_pos != (Vector2{x: x, y: y}) {
+                        ret.push(new_pos);
+                        dirty_cells.insert(new_pos);
+                    }
+                },
             }
         }
 
         return ret;
     }
 
+    // Turns the particle at (x,y) into its colder or hotter phase, if its temperature crossed one of the material thresholds.
+    // Returns the changed cells
+    fn change_phase(&mut self, x: usize, y: usize, dirty_cells: &mut HashSet<Vector2<usize>>) -> Vec<Vector2<usize>> {
+        let materials = self.materials.clone();
+        let material = match materials.of(self.get(x, y)) {
+            Some(material) => material,
+            None => return vec![],
+        };
+        let temperature = self.temperature(x, y);
+        let concentration = self.get(x, y).particle_data().map_or(0.0, |data| data.concentration);
+        // dissolved solute makes it freeze colder
+        let freezing_drop = material.solution.as_ref().map_or(0.0, |solution| solution.freezing_drop * concentration);
+        let colder = material.colder_phase.as_ref().filter(|phase| temperature < phase.temperature - freezing_drop);
+        let hotter = material.hotter_phase.as_ref().filter(|phase| temperature > phase.temperature);
+        let new_material = match colder.or(hotter).and_then(|phase| materials.id(&phase.into)) {
+            Some(new_material) => new_material,
+            None => return vec![],
+        };
+
+        // the new phase can't hold the solute
+        let (mut ret, crystallized) = self.crystallize(x, y, dirty_cells);
+        if !crystallized {
+            return ret;
+        }
+        let new_cell = self.new_particle(new_material);
+        self.grid.set(x, y, new_cell);
+        dirty_cells.insert(Vector2{x: x, y: y});
+        ret.push(Vector2{x: x, y: y});
+        ret
+    }
+
+    // Gets the solute out of the solvent particle at (x,y) before it turns into something else: the neighbors still in
+    // solution take what they can, and the rest crystallizes into the empty cells around, with a fraction of a particle
+    // making one by chance. What there isn't room for yet stays dissolved in the particle, to come out on the next ticks.
+    // Returns the changed cells and whether the particle got rid of all of it
+    fn crystallize(&mut self, x: usize, y: usize, dirty_cells: &mut HashSet<Vector2<usize>>) -> (Vec<Vector2<usize>>, bool) {
+        let materials = self.materials.clone();
+        let cell = self.get(x, y).clone();
+        let mut left = cell.particle_data().map_or(0.0, |data| data.concentration);
+        let solution = match materials.of(&cell).and_then(|material| material.solution.as_ref()) {
+            Some(solution) if left > 0.0 => solution,
+            _ => return (vec![], true),
+        };
+        let mut ret = vec![];
+        for neighbor in self.neighbors(x, y) {
+            if self.get(neighbor.x, neighbor.y).material() != cell.material() {
+                continue;
+            }
+            let data = self.grid.get_mut(neighbor.x, neighbor.y).particle_data_mut().unwrap();
+            let taken = left.min(1.0 - data.concentration);
+            if taken > 0.0 {
+                data.concentration += taken;
+                left -= taken;
+                ret.push(neighbor);
+            }
+        }
+
+        let particles = left / solution.per_particle;
+        let crystals = particles.floor() as usize + self.gen_bool((particles - particles.floor()) as f64) as usize;
+        let mut room : Vec<Vector2<usize>> = self.neighbors(x, y).into_iter()
+            .filter(|at| *self.get(at.x, at.y) == CellType::Empty && !dirty_cells.contains(at))
+            .collect();
+        let done = room.len() >= crystals;
+        room.shuffle(&mut self.rng);
+        let solute = materials.id(&solution.solute).unwrap();
+        for at in room.into_iter().take(crystals) {
+            let crystal = self.new_particle(solute);
+            self.set(at.x, at.y, crystal);
+            dirty_cells.insert(at);
+            ret.push(at);
+            left -= solution.per_particle;
+        }
+        self.grid.get_mut(x, y).particle_data_mut().unwrap().concentration = if done { 0.0 } else { left };
+        (ret, done)
+    }
+
+    // A solvent particle at (x,y) may dissolve a touching solute particle, then evens out its concentration with a random
+    // neighbor of its own kind. Returns the changed cells
+    fn dissolve(&mut self, x: usize, y: usize, dirty_cells: &mut HashSet<Vector2<usize>>) -> Vec<Vector2<usize>> {
+        let materials = self.materials.clone();
+        let cell = self.get(x, y).clone();
+        let solution = match materials.of(&cell).and_then(|material| material.solution.as_ref()) {
+            Some(solution) => solution,
+            None => return vec![],
+        };
+        let mut ret = vec![];
+        let mut concentration = cell.particle_data().unwrap().concentration;
+
+        if concentration + solution.per_particle <= 1.0 && self.gen_bool(solution.chance) {
+            let solutes : Vec<Vector2<usize>> = self.neighbors(x, y).into_iter()
+                .filter(|at| !dirty_cells.contains(at))
+                .filter(|at| materials.of(self.get(at.x, at.y)).map_or(false, |neighbor| neighbor.name == solution.solute))
+                .collect();
+            if let Some(&solute) = solutes.choose(&mut self.rng) {
+                self.set(solute.x, solute.y, CellType::Empty);
+                concentration += solution.per_particle;
+                dirty_cells.insert(solute);
+                ret.push(solute);
+                ret.push(Vector2{x: x, y: y});
+            }
+        }
+
+        let (dx, dy) = AROUND[self.gen_range(0..=7)];
+        if let Some(other) = self.offset(x, y, (dx, dy)).filter(|other| !dirty_cells.contains(other)) {
+            if self.get(other.x, other.y).material() == cell.material() {
+                let data = self.grid.get_mut(other.x, other.y).particle_data_mut().unwrap();
+                let exchange = (data.concentration - concentration) * MIXING;
+                if exchange.abs() >= MIXING_THRESHOLD {
+                    data.concentration -= exchange;
+                    concentration += exchange;
+                    ret.push(other);
+                    ret.push(Vector2{x: x, y: y});
+                }
+            }
+        }
+        self.grid.get_mut(x, y).particle_data_mut().unwrap().concentration = concentration;
+        ret
+    }
+
+    // Runs the reactions of the particle at (x,y) against its neighbors, stopping at the first one that happens.
+    // Returns the changed cells
+    fn react(&mut self, x: usize, y: usize, dirty_cells: &mut HashSet<Vector2<usize>>) -> Vec<Vector2<usize>> {
+        let materials = self.materials.clone();
+        let material = match materials.of(self.get(x, y)) {
+            Some(material) if !material.reactions.is_empty() => material,
+            _ => return vec![],
+        };
+        for other in self.neighbors(x, y) {
+            if dirty_cells.contains(&other) {
+                continue;
+            }
+            let neighbor = match materials.of(self.get(other.x, other.y)) {
+                Some(neighbor) => neighbor,
+                None => continue,
+            };
+            for reaction in material.reactions.iter().filter(|reaction| reaction.with == neighbor.name) {
+                if !self.gen_bool(reaction.chance) {
+                    continue;
+                }
+                let product = self.new_product(&reaction.into);
+                let (mut ret, replaced) = self.replace_solvent(x, y, product, dirty_cells);
+                if !replaced || reaction.other_stays {
+                    return ret;
+                }
+                let other_product = self.new_product(&reaction.other_into);
+                ret.extend(self.replace_solvent(other.x, other.y, other_product, dirty_cells).0);
+                return ret;
+            }
+        }
+        vec![]
+    }
+
+    // Puts the product of a reaction at (x,y). If the particle there is a solvent, the product carries its solute over when
+    // it's the same solvent, and otherwise it crystallizes out first, the product waiting if there's no room for it.
+    // Returns the changed cells and whether the product got there
+    fn replace_solvent(&mut self, x: usize, y: usize, product: CellType, dirty_cells: &mut HashSet<Vector2<usize>>) -> (Vec<Vector2<usize>>, bool) {
+        let mut product = product;
+        let mut ret = vec![];
+        if product.material().is_some() && product.material() == self.get(x, y).material() {
+            let concentration = self.get(x, y).particle_data().unwrap().concentration;
+            product.particle_data_mut().unwrap().concentration = concentration;
+        } else {
+            let (changed, crystallized) = self.crystallize(x, y, dirty_cells);
+            ret = changed;
+            if !crystallized {
+                return (ret, false);
+            }
+        }
+        self.set(x, y, product);
+        dirty_cells.insert(Vector2{x: x, y: y});
+        ret.push(Vector2{x: x, y: y});
+        (ret, true)
+    }
+
+    // A corrosive particle at (x,y) may eat one of its neighbors, getting weaker each time.
+    // Returns the changed cells and whether the particle got used up
+    fn corrode(&mut self, x: usize, y: usize, dirty_cells: &mut HashSet<Vector2<usize>>) -> (Vec<Vector2<usize>>, bool) {
+        let materials = self.materials.clone();
+        let corrosion = match materials.of(self.get(x, y)).and_then(|material| material.corrosion.as_ref()) {
+            Some(corrosion) => corrosion,
+            None => return (vec![], false),
+        };
+        let strength = self.get(x, y).particle_data().and_then(|data| data.strength).unwrap_or(corrosion.strength);
+        // weaker acid eats slower
+        if !self.gen_bool(corrosion.chance * strength as f64 / corrosion.strength as f64) {
+            return (vec![], false);
+        }
+        let edible : Vec<Vector2<usize>> = self.neighbors(x, y).into_iter()
+            .filter(|at| !dirty_cells.contains(at))
+            .filter(|at| materials.of(self.get(at.x, at.y)).map_or(false, |neighbor| neighbor.corrosion.is_none() && !neighbor.acid_proof))
+            .collect();
+        let eaten = match edible.choose(&mut self.rng) {
+            Some(eaten) => *eaten,
+            None => return (vec![], false),
+        };
+        self.set(eaten.x, eaten.y, CellType::Empty);
+        dirty_cells.insert(eaten);
+        let used_up = strength <= 1;
+        if used_up {
+            self.set(x, y, CellType::Empty);
+            dirty_cells.insert(Vector2{x: x, y: y});
+        } else if let Some(data) = self.grid.get_mut(x, y).particle_data_mut() {
+            data.strength = Some(strength - 1);
+        }
+        (vec![Vector2{x: x, y: y}, eaten], used_up)
+    }
+
+    // A growing particle at (x,y) may drink one of its neighbors to gather energy, or spend it growing upwards.
+    // Returns the changed cells
+    fn grow(&mut self, x: usize, y: usize, dirty_cells: &mut HashSet<Vector2<usize>>) -> Vec<Vector2<usize>> {
+        let materials = self.materials.clone();
+        let cell = self.get(x, y).clone();
+        let growth = match materials.of(&cell).and_then(|material| material.growth.as_ref()) {
+            Some(growth) => growth,
+            None => return vec![],
+        };
+        let into = match materials.id(&growth.into) {
+            Some(into) => into,
+            None => return vec![],
+        };
+        // seeds only take root once they landed somewhere, and grow away from the pull. Weightless, anything they touch will do
+        let down = heading(self.pull_at(x, y));
+        let landed = match down {
+            Some(down) => self.offset(x, y, AROUND[down]).map_or(true, |below| *self.get(below.x, below.y) != CellType::Empty),
+            None => self.touches(x, y, |neighbor| *neighbor != CellType::Empty),
+        };
+        if !landed {
+            return vec![];
+        }
+        let upwards : Vec<(i32, i32)> = match down {
+            Some(down) => (3..=5).map(|turns| AROUND[(down + turns) % 8]).collect(),
+            None => AROUND.to_vec(),
+        };
+        let energy = cell.particle_data().map_or(0, |data| data.energy);
+
+        // only thirsty once the energy gathered is spent
+        let drinkable : Vec<Vector2<usize>> = self.neighbors(x, y).into_iter()
+            .filter(|at| energy == 0 && !dirty_cells.contains(at))
+            .filter(|at| materials.of(self.get(at.x, at.y)).map_or(false, |neighbor| neighbor.name == growth.drinks))
+            .collect();
+        let mut room = vec![];
+        if energy > 0 && cell.material() == Some(into) {
+            for offset in upwards {
+                match self.offset(x, y, offset) {
+                    Some(at) if *self.get(at.x, at.y) == CellType::Empty && !dirty_cells.contains(&at) => room.push(at),
+                    _ => {},
+                }
+            }
+        }
+        if drinkable.is_empty() && room.is_empty() {
+            return vec![];
+        }
+        // growth is slow, keep the room awake until it's done
+        self.hot(x, y);
+        if !self.gen_bool(growth.chance) {
+            return vec![];
+        }
+
+        if let Some(drunk) = drinkable.choose(&mut self.rng).copied() {
+            self.set(drunk.x, drunk.y, CellType::Empty);
+            let mut grown = if cell.material() == Some(into) { cell } else { self.new_particle(into) };
+            if let Some(data) = grown.particle_data_mut() {
+                data.energy = energy + growth.energy;
+            }
+            self.set(x, y, grown);
+            dirty_cells.insert(drunk);
+            dirty_cells.insert(Vector2{x: x, y: y});
+            return vec![Vector2{x: x, y: y}, drunk];
+        }
+
+        room.shuffle(&mut self.rng);
+        let branches = if energy >= 2 && self.gen_bool(growth.branching) { 2 } else { 1 };
+        let mut ret = vec![];
+        for (i, new_pos) in room.into_iter().take(branches).enumerate() {
+            // the energy left is shared between the branches
+            let share = (energy - 1) / branches as u32 + if i == 0 { (energy - 1) % branches as u32 } else { 0 };
+            let mut sprout = self.new_particle(into);
+            if let Some(data) = sprout.particle_data_mut() {
+                data.energy = share;
+            }
+            self.set(new_pos.x, new_pos.y, sprout);
+            dirty_cells.insert(new_pos);
+            ret.push(new_pos);
+        }
+        if let Some(data) = self.grid.get_mut(x, y).particle_data_mut() {
+            data.energy = 0;
+        }
+        ret
+    }
+
+    // Moves electric pulses along conductors, lets power sources emit new ones and runs powered devices.
+    // Returns the changed cells
+    fn electrify(&mut self, x: usize, y: usize, dirty_cells: &mut HashSet<Vector2<usize>>) -> Vec<Vector2<usize>> {
+        let materials = self.materials.clone();
+        let material = match materials.of(self.get(x, y)) {
+            Some(material) => material,
+            None => return vec![],
+        };
+        let mut ret = vec![];
+
+        if material.conducts {
+            let charge = self.get(x, y).particle_data().map_or(0, |data| data.charge);
+            if charge == CHARGED {
+                self.charge_neighbors(x, y);
+            }
+            if charge > 0 {
+                if let Some(data) = self.grid.get_mut(x, y).particle_data_mut() {
+                    data.charge = charge - 1;
+                }
+                ret.push(Vector2{x: x, y: y});
+            }
+        }
+
+        if let Some(period) = material.power {
+            // sources never sleep
+            self.hot(x, y);
+            if self.tick % period as u64 == 0 {
+                self.charge_neighbors(x, y);
+            }
+        }
+
+        if let Some(device) = &material.device {
+            let powered = self.touches(x, y, |neighbor| neighbor.particle_data().map_or(false, |data| data.charge > 0));
+            if powered {
+                match device {
+                    Device::Heater { heat } => {
+                        *self.temperature.get_mut(x, y) += heat;
+                        self.hot(x, y);
+                    },
+                    Device::Igniter => {
+                        if let Some(flame) = materials.flame() {
+                            ret.extend(self.emit(x, y, flame, dirty_cells));
+                        }
+                    },
+                    Device::Emitter { emits } => {
+                        if let Some(emits) = materials.id(emits) {
+                            ret.extend(self.emit(x, y, emits, dirty_cells));
+                        }
+                    },
+                }
+            }
+        }
+        ret
+    }
+
+    // Sends a pulse to the idle conductors around (x,y), which get it at the end of the tick
+    fn charge_neighbors(&mut self, x: usize, y: usize) {
+        for neighbor in self.neighbors(x, y) {
+            if self.is_idle_conductor(neighbor.x, neighbor.y) {
+                self.charging.push(neighbor);
+            }
+        }
+    }
+
+    fn is_idle_conductor(&self, x: usize, y: usize) -> bool {
+        self.materials.of(self.get(x, y)).map_or(false, |material| material.conducts)
+            && self.get(x, y).particle_data().map_or(false, |data| data.charge == 0)
+    }
+
+    // Charges the conductors the pulses reached this tick, if nothing took their place meanwhile. Returns the cells that got charged
+    fn charge_conductors(&mut self) -> Vec<Vector2<usize>> {
+        let mut ret = vec![];
+        for cell in std::mem::take(&mut self.charging) {
+            if self.is_idle_conductor(cell.x, cell.y) {
+                self.grid.get_mut(cell.x, cell.y).particle_data_mut().unwrap().charge = CHARGED;
+                ret.push(cell);
+            }
+        }
+        ret
+    }
+
+    // Spawns a particle of the material in a random empty cell around (x,y). Returns the changed cells
+    fn emit(&mut self, x: usize, y: usize, material: MaterialId, dirty_cells: &mut HashSet<Vector2<usize>>) -> Vec<Vector2<usize>> {
+        let empty : Vec<Vector2<usize>> = self.neighbors(x, y).into_iter()
+            .filter(|at| *self.get(at.x, at.y) == CellType::Empty)
+            .collect();
+        let cell = match empty.choose(&mut self.rng) {
+            Some(cell) => *cell,
+            None => return vec![],
+        };
+        let particle = self.new_particle(material);
+        self.set(cell.x, cell.y, particle);
+        dirty_cells.insert(cell);
+        vec![cell]
+    }
+
+    // Whether the particle at (x,y) is still shifting its color with age. It then needs redrawing every tick,
+    // and keeps its room awake until it's done
+    fn is_aging(&mut self, x: usize, y: usize) -> bool {
+        let cell = self.get(x, y);
+        let aging = match (self.materials.of(cell).and_then(|material| material.aging.as_ref()), cell.particle_data()) {
+            (Some(aging), Some(data)) => self.age(data) <= aging.ticks as u64,
+            _ => false,
+        };
+        if aging {
+            self.hot(x, y);
+        }
+        aging
+    }
+
+    // Counts down the lifetime of a particle that doesn't fade away like gases do, turning it into one of its
+    // decay outcomes once it runs out. Returns whether it decayed
+    fn decay(&mut self, x: usize, y: usize) -> bool {
+        let materials = self.materials.clone();
+        let material = match materials.of(self.get(x, y)) {
+            Some(material) if material.movement != Movement::Gas => material,
+            _ => return false,
+        };
+        let lifetime = match self.get(x, y).particle_data().and_then(|data| data.lifetime) {
+            Some(lifetime) => lifetime,
+            None => return false,
+        };
+        if lifetime <= 1 {
+            let leftover = self.random_outcome(&material.decays_into);
+            self.set(x, y, leftover);
+            return true;
+        }
+        if let Some(data) = self.grid.get_mut(x, y).particle_data_mut() {
+            data.lifetime = Some(lifetime - 1);
+        }
+        // keeps counting even when nothing else happens around
+        self.hot(x, y);
+        false
+    }
+
+    // Whether the particle at (x,y) has enough of its own kind beside and above it to hang from, with the given AROUND direction as down
+    fn clings(&self, x: usize, y: usize, down: usize) -> bool {
+        let material = self.get(x, y).material();
+        let mut holds = 0;
+        for turns in [2, 3, 4, 5, 6] {
+            if let Some(neighbor) = self.offset(x, y, AROUND[(down + turns) % 8]) {
+                if self.get(neighbor.x, neighbor.y).material() == material {
+                    holds += 1;
+                }
+            }
+        }
+        holds >= 2
+    }
+
+    // Whether the particle at (x,y) is an explosive that got too hot or touches a flame
+    fn should_detonate(&self, x: usize, y: usize) -> bool {
+        let explosion = match self.materials.of(self.get(x, y)).and_then(|material| material.explosion.as_ref()) {
+            Some(explosion) => explosion,
+            None => return false,
+        };
+        self.temperature(x, y) > explosion.temperature
+            || self.touches(x, y, |neighbor| self.materials.of(neighbor).map_or(false, |neighbor| neighbor.flame))
+    }
+
+    // Blows up the explosive at (x,y), along with every other explosive caught in the blast.
+    // Returns the changed cells
+    fn detonate(&mut self, x: usize, y: usize, dirty_cells: &mut HashSet<Vector2<usize>>) -> Vec<Vector2<usize>> {
+        let materials = self.materials.clone();
+        let mut ret = vec![];
+        let mut pending = vec![(Vector2{x: x, y: y}, materials.of(self.get(x, y)).unwrap().explosion.clone().unwrap())];
+        while let Some((center, explosion)) = pending.pop() {
+            for cell in self.disc(center.x, center.y, explosion.radius*2) {
+                let dx = cell.x as f32 - center.x as f32;
+                let dy = cell.y as f32 - center.y as f32;
+                let distance = (dx*dx + dy*dy).sqrt();
+                if distance <= explosion.radius as f32 {
+                    if let Some(chained) = materials.of(self.get(cell.x, cell.y)).and_then(|material| material.explosion.clone()) {
+                        if cell != center {
+                            pending.push((cell, chained));
+                        }
+                    }
+                    let leftover = match materials.flame() {
+                        Some(flame) if self.gen_bool(0.3) => self.new_particle(flame),
+                        _ => CellType::Empty,
+                    };
+                    self.set(cell.x, cell.y, leftover);
+                    let temperature = self.temperature.get_mut(cell.x, cell.y);
+                    *temperature = temperature.max(BURNING_TEMPERATURE);
+                    dirty_cells.insert(cell);
+                    ret.push(cell);
+                    continue;
+                }
+                let flung = materials.of(self.get(cell.x, cell.y))
+                    .map_or(false, |material| material.movement == Movement::Powder || material.movement == Movement::Liquid);
+                if !flung {
+                    continue;
+                }
+                // the closer to the blast, the harder it gets pushed away
+                let push = explosion.power * (3.0 - distance / explosion.radius as f32);
+                if let Some(data) = self.grid.get_mut(cell.x, cell.y).particle_data_mut() {
+                    data.speed = Vector2{x: dx / distance * push, y: dy / distance * push};
+                }
+                self.hot(cell.x, cell.y);
+            }
+        }
+        ret
+    }
+
+    // A fresh particle of the named material, or an empty cell if there's none
+    fn new_product(&mut self, material: &Option<String>) -> CellType {
+        match material.as_ref().and_then(|name| self.materials.id(name)) {
+            Some(material) => self.new_particle(material),
+            None => CellType::Empty,
+        }
+    }
+
+    // Follows the speed of the mover particle at (x,y) one cell at a time, never cutting corners,
+    // and stops right before the first obstacle or the edge of the world
+    // Right at the start, it can sink into the lighter particle below it
+    fn trace(&mut self, x: usize, y: usize, mover: &CellType, speed: Vector2<f32>, below: Option<Vector2<usize>>, dirty_cells: &HashSet<Vector2<usize>>) -> Trace {
+        let reach = speed.x.abs().max(speed.y.abs());
+        let steps = if self.gen_bool(0.5) { reach.floor() } else { reach.ceil() } as usize;
+        let mut trace = Trace{end: Vector2{x: x, y: y}, hit_x: false, hit_y: false, sinking: false};
+        for step in 1..=steps {
+            let t = step as f32 / reach;
+            let target_x = x as i32 + (speed.x * t).round() as i32;
+            let target_y = y as i32 + (speed.y * t).round() as i32;
+            // diagonal steps go sideways first, then up or down
+            while (trace.end.x as i32, trace.end.y as i32) != (target_x, target_y) {
+                let sideways = trace.end.x as i32 != target_x;
+                let (nx, ny) = if sideways {
+                    (trace.end.x as i32 + (target_x - trace.end.x as i32).signum(), trace.end.y as i32)
+                } else {
+                    (trace.end.x as i32, trace.end.y as i32 + (target_y - trace.end.y as i32).signum())
+                };
+                let next = Vector2{x: nx as usize, y: ny as usize};
+                let inside = nx >= 0 && ny >= 0 && nx < self.width() as i32 && ny < self.height() as i32;
+                if inside && *self.get(next.x, next.y) == CellType::Empty && !dirty_cells.contains(&next) {
+                    trace.end = next;
+                    continue;
+                }
+                if inside && step == 1 && trace.end == (Vector2{x: x, y: y}) && Some(next) == below
+                    && !dirty_cells.contains(&next) && self.passable(mover, next.x, next.y) {
+                    trace.end = next;
+                    trace.sinking = true;
+                    return trace;
+                }
+                trace.hit_x = sideways;
+                trace.hit_y = !sideways;
+                return trace;
+            }
+        }
+        trace
+    }
+
+    // Gathers the rigid particles set since the last tick into bodies, then moves every body along its speed.
+    // Returns the changed cells
+    fn integrate_bodies(&mut self) -> Vec<Vector2<usize>> {
+        let mut ret = vec![];
+        for start in std::mem::take(&mut self.unbound) {
+            if self.is_unbound_rigid(start) {
+                self.bind_body(start);
+            }
+        }
+        for body in std::mem::take(&mut self.bodies) {
+            if let Some(body) = self.step_body(body, &mut ret) {
+                self.bodies.push(body);
+            }
+        }
+        ret
+    }
+
+    // Whether there's a rigid particle at the position that isn't part of any body yet
+    fn is_unbound_rigid(&self, at: Vector2<usize>) -> bool {
+        let cell = self.get(at.x, at.y);
+        self.materials.of(cell).map_or(false, |material| material.rigid.is_some())
+            && cell.particle_data().map_or(false, |data| data.body.is_none())
+    }
+
+    // Makes a new body out of the unbound rigid particles connected to start, along with the bodies they touch
+    fn bind_body(&mut self, start: Vector2<usize>) {
+        let mut positions = vec![];
+        let mut touched = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![start];
+        while let Some(at) = stack.pop() {
+            if !seen.insert(at) {
+                continue;
+            }
+            if self.is_unbound_rigid(at) {
+                positions.push(at);
+                for offset in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                    if let Some(next) = self.offset(at.x, at.y, offset) {
+                        stack.push(next);
+                    }
+                }
+            } else if let Some(id) = self.get(at.x, at.y).particle_data().and_then(|data| data.body) {
+                touched.insert(id);
+            }
+        }
+
+        let (merged, kept) : (Vec<RigidBody>, Vec<RigidBody>) = std::mem::take(&mut self.bodies).into_iter()
+            .partition(|body| touched.contains(&body.id));
+        self.bodies = kept;
+        let mut velocity = Vector2{x: 0.0, y: 0.0};
+        for body in merged.iter() {
+            velocity.x += body.velocity.x / merged.len() as f32;
+            velocity.y += body.velocity.y / merged.len() as f32;
+            positions.extend(body.placed.iter().flatten());
+        }
+
+        let id = self.next_body;
+        self.next_body += 1;
+        let center = Vector2{
+            x: positions.iter().map(|at| at.x as f32).sum::<f32>() / positions.len() as f32,
+            y: positions.iter().map(|at| at.y as f32).sum::<f32>() / positions.len() as f32,
+        };
+        let mut cells = vec![];
+        for at in positions.iter() {
+            if let Some(data) = self.grid.get_mut(at.x, at.y).particle_data_mut() {
+                data.body = Some(id);
+            }
+            cells.push((Vector2{x: at.x as f32 - center.x, y: at.y as f32 - center.y}, self.get(at.x, at.y).clone()));
+        }
+        self.bodies.push(RigidBody{
+            id: id,
+            cells: cells,
+            position: center,
+            velocity: velocity,
+            angle: 0.0,
+            spin: 0.0,
+            placed: positions.into_iter().map(Some).collect(),
+        });
+    }
+
+    // Lifts the body out of the grid, moves it as far as it fits, bouncing off or breaking apart on what it hits,
+    // and writes it back. Returns the body, unless nothing is left of it
+    fn step_body(&mut self, mut body: RigidBody, ret: &mut Vec<Vector2<usize>>) -> Option<RigidBody> {
+        // particles burnt, eaten or erased since the last tick aren't part of the body anymore
+        let mut cells = vec![];
+        let mut vacated = vec![];
+        for ((offset, cell), placed) in body.cells.into_iter().zip(body.placed.into_iter()) {
+            match placed {
+                Some(at) => {
+                    let current = self.get(at.x, at.y).clone();
+                    if current.particle_data().map_or(false, |data| data.body == Some(body.id)) {
+                        cells.push((offset, current));
+                        self.grid.set(at.x, at.y, CellType::Empty);
+                        vacated.push(at);
+                    }
+                },
+                None => cells.push((offset, cell)),
+            }
+        }
+        body.cells = cells;
+        body.placed = vec![];
+        if body.cells.is_empty() {
+            return None;
+        }
+
+        let materials = self.materials.clone();
+        let material = materials.of(&body.cells[0].1).unwrap();
+        let center = Vector2{
+            x: body.position.x.round().clamp(0.0, self.width() as f32 - 1.0) as usize,
+            y: body.position.y.round().clamp(0.0, self.height() as f32 - 1.0) as usize,
+        };
+        let pull = self.pull_at(center.x, center.y);
+        body.velocity.x += pull.x;
+        body.velocity.y += pull.y;
+        body.spin *= SPIN_DAMPING;
+
+        // moving a cell at most on every step, so it can't go through anything
+        let reach = body.cells.iter().map(|(offset, _)| (offset.x*offset.x + offset.y*offset.y).sqrt()).fold(0.0, f32::max);
+        let steps = body.velocity.x.abs().max(body.velocity.y.abs()).max(body.spin.abs() * reach).ceil().max(1.0);
+        let step = Vector2{x: body.velocity.x / steps, y: body.velocity.y / steps};
+        let turn = body.spin / steps;
+        let start = body.position;
+        let mut hits = vec![];
+        for _ in 0..steps as usize {
+            let position = Vector2{x: body.position.x + step.x, y: body.position.y + step.y};
+            hits = self.body_hits(&body, position, body.angle + turn);
+            if !hits.is_empty() {
+                break;
+            }
+            body.position = position;
+            body.angle += turn;
+        }
+
+        if !hits.is_empty() {
+            let contact = Vector2{
+                x: hits.iter().map(|hit| hit.x).sum::<f32>() / hits.len() as f32,
+                y: hits.iter().map(|hit| hit.y).sum::<f32>() / hits.len() as f32,
+            };
+            let impact = (body.velocity.x*body.velocity.x + body.velocity.y*body.velocity.y).sqrt();
+            if impact >= material.rigid.as_ref().unwrap().shatter_speed {
+                self.shatter(&body, ret);
+                ret.extend(vacated);
+                return None;
+            }
+            let before = body.velocity;
+            let blocked_x = !self.body_hits(&body, Vector2{x: body.position.x + step.x, y: body.position.y}, body.angle).is_empty();
+            let blocked_y = !self.body_hits(&body, Vector2{x: body.position.x, y: body.position.y + step.y}, body.angle).is_empty();
+            let blocked_turn = !self.body_hits(&body, body.position, body.angle + turn).is_empty();
+            // what it slides along rubs its speed and spin away
+            if blocked_x || !(blocked_y || blocked_turn) {
+                body.velocity.x = bounce(body.velocity.x, material.restitution);
+                body.velocity.y *= 1.0 - material.friction;
+            }
+            if blocked_y || !(blocked_x || blocked_turn) {
+                body.velocity.y = bounce(body.velocity.y, material.restitution);
+                body.velocity.x *= 1.0 - material.friction;
+            }
+            if blocked_turn {
+                body.spin = -body.spin * material.restitution;
+            }
+            body.spin *= 1.0 - material.friction;
+            // hits away from the center set it spinning
+            let inertia = body.cells.iter().map(|(offset, _)| offset.x*offset.x + offset.y*offset.y).sum::<f32>().max(1.0);
+            let change = Vector2{x: body.velocity.x - before.x, y: body.velocity.y - before.y};
+            body.spin += (contact.x * change.y - contact.y * change.x) * body.cells.len() as f32 / inertia;
+
+            // stuck bodies with their weight past what holds them tip over it, else slide off it
+            let weight = (pull.x*pull.x + pull.y*pull.y).sqrt().max(f32::EPSILON);
+            let across = |hit: &Vector2<f32>| (hit.y * pull.x - hit.x * pull.y) / weight;
+            let balanced = hits.iter().any(|hit| across(hit) >= -0.25) && hits.iter().any(|hit| across(hit) <= 0.25);
+            if body.position == start && !balanced {
+                let torque = contact.y * pull.x - contact.x * pull.y;
+                let tip = reach.recip().copysign(torque);
+                let pivot = Vector2{x: body.position.x + contact.x, y: body.position.y + contact.y};
+                let around_pivot = turned(contact, tip);
+                let tipped = Vector2{x: pivot.x - around_pivot.x, y: pivot.y - around_pivot.y};
+                if self.body_hits(&body, tipped, body.angle + tip).is_empty() {
+                    body.position = tipped;
+                    body.angle += tip;
+                } else {
+                    // pushed off what it rests on, along its pull
+                    let length = |v: Vector2<f32>| (v.x*v.x + v.y*v.y).sqrt().max(f32::EPSILON);
+                    let push = Vector2{
+                        x: pull.x / length(pull) - contact.x / length(contact),
+                        y: pull.y / length(pull) - contact.y / length(contact),
+                    };
+                    let mut nudges : Vec<(f32, Vector2<f32>)> = AROUND.iter()
+                        .map(|&(dx, dy)| {
+                            let nudge = Vector2{x: dx as f32, y: dy as f32};
+                            ((nudge.x*push.x + nudge.y*push.y) / length(nudge), nudge)
+                        })
+                        .filter(|(score, nudge)| *score > 0.01 && nudge.x*pull.x + nudge.y*pull.y >= 0.0)
+                        .collect();
+                    nudges.sort_by(|a, b| b.0.total_cmp(&a.0));
+                    let nudged = nudges.iter()
+                        .map(|(_, nudge)| Vector2{x: body.position.x + nudge.x, y: body.position.y + nudge.y})
+                        .find(|position| self.body_hits(&body, *position, body.angle).is_empty());
+                    if let Some(position) = nudged {
+                        body.position = position;
+                    }
+                }
+            }
+        }
+
+        self.place_body(&mut body, &vacated, ret);
+        Some(body)
+    }
+
+    // Where the i-th cell of the body lands, with the body at that position and turn. None when it's outside of the world
+    fn body_cell(&self, body: &RigidBody, i: usize, position: Vector2<f32>, angle: f32) -> Option<Vector2<usize>> {
+        let offset = turned(body.cells[i].0, angle);
+        let x = (position.x + offset.x).round();
+        let y = (position.y + offset.y).round();
+        if x < 0.0 || y < 0.0 || x >= self.width() as f32 || y >= self.height() as f32 {
+            return None;
+        }
+        Some(Vector2{x: x as usize, y: y as usize})
+    }
+
+    // The cells the body would run into at that position and turn, from its center. Empty when it fits there.
+    // Gases and lighter liquids don't stop it, they get pushed away instead
+    fn body_hits(&self, body: &RigidBody, position: Vector2<f32>, angle: f32) -> Vec<Vector2<f32>> {
+        let density = self.density(&body.cells[0].1);
+        let mut hits = vec![];
+        for i in 0..body.cells.len() {
+            let blocked = match self.body_cell(body, i, position, angle) {
+                None => true,
+                Some(at) => {
+                    let cell = self.get(at.x, at.y);
+                    *cell != CellType::Empty && !self.materials.of(cell).map_or(false, |material| {
+                        material.movement == Movement::Gas || (material.movement == Movement::Liquid && material.density < density)
+                    })
+                },
+            };
+            if blocked {
+                let offset = turned(body.cells[i].0, angle);
+                hits.push(Vector2{x: (position.x + offset.x).round() - position.x, y: (position.y + offset.y).round() - position.y});
+            }
+        }
+        hits
+    }
+
+    // Writes the body into the grid where it stands. The particles it pushes away take the cells it left
+    fn place_body(&mut self, body: &mut RigidBody, vacated: &[Vector2<usize>], ret: &mut Vec<Vector2<usize>>) {
+        let mut displaced = vec![];
+        let mut taken = HashSet::new();
+        for i in 0..body.cells.len() {
+            match self.body_cell(body, i, body.position, body.angle) {
+                Some(at) if taken.insert(at) => {
+                    let pushed = self.get(at.x, at.y).clone();
+                    if pushed != CellType::Empty {
+                        displaced.push(pushed);
+                    }
+                    self.grid.set(at.x, at.y, body.cells[i].1.clone());
+                    body.placed.push(Some(at));
+                    if !vacated.contains(&at) {
+                        ret.push(at);
+                    }
+                },
+                _ => body.placed.push(None),
+            }
+        }
+        let mut left : Vec<Vector2<usize>> = vacated.iter().filter(|at| !taken.contains(at)).cloned().collect();
+        ret.extend(left.iter());
+        for particle in displaced {
+            match left.pop() {
+                Some(at) => self.grid.set(at.x, at.y, particle),
+                None => break,
+            }
+        }
+    }
+
+    // Breaks the body into debris, thrown back from what it hit
+    fn shatter(&mut self, body: &RigidBody, ret: &mut Vec<Vector2<usize>>) {
+        for i in 0..body.cells.len() {
+            let at = match self.body_cell(body, i, body.position, body.angle) {
+                Some(at) if *self.get(at.x, at.y) == CellType::Empty => at,
+                _ => continue,
+            };
+            let debris = self.materials.of(&body.cells[i].1).and_then(|material| material.rigid.as_ref()).map(|rigid| rigid.debris.clone());
+            let mut particle = self.new_product(&debris);
+            let rebound = self.gen_range(0.1..=0.4);
+            let scatter = Vector2{x: self.gen_range(-1.0..=1.0), y: self.gen_range(-1.0..=1.0)};
+            if let Some(data) = particle.particle_data_mut() {
+                data.speed = Vector2{x: -body.velocity.x * rebound + scatter.x, y: -body.velocity.y * rebound + scatter.y};
+            }
+            self.set(at.x, at.y, particle);
+            ret.push(at);
+        }
+    }
+
+    // Ties the rope particles set since the last tick to their neighbors, then swings every knot under its pull and load,
+    // pulls linked knots back together, snapping the links stretched too far, and moves the knots through the grid.
+    // Returns the changed cells
+    fn solve_ropes(&mut self) -> Vec<Vector2<usize>> {
+        let mut ret = vec![];
+        for at in std::mem::take(&mut self.untied) {
+            self.tie_knot(at);
+        }
+        if self.knots.is_empty() {
+            return ret;
+        }
+
+        // knots burnt, eaten or erased since the last tick are gone, and so is what some of them were tied to
+        let grid = &self.grid;
+        self.knots.retain(|id, knot| grid.get(knot.cell.x, knot.cell.y).particle_data().map_or(false, |data| data.knot == Some(*id)));
+        let loosened : Vec<u32> = self.knots.iter()
+            .filter(|(_, knot)| knot.anchor.map_or(false, |at| !self.holds_ropes(self.get(at.x, at.y))))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in loosened {
+            self.knots.get_mut(&id).unwrap().anchor = None;
+        }
+        let mut ids : Vec<u32> = self.knots.keys().cloned().collect();
+        let mut links = vec![];
+        for id in ids.iter() {
+            let cell = self.knots[id].cell;
+            for other in self.get(cell.x, cell.y).particle_data().unwrap().links.iter() {
+                if id < other && self.knots.contains_key(other) {
+                    links.push((*id, *other));
+                }
+            }
+        }
+
+        for id in ids.iter() {
+            let mut knot = self.knots[id];
+            if knot.anchor.is_some() {
+                continue;
+            }
+            let pull = self.pull_at(knot.cell.x, knot.cell.y);
+            let weight = 1.0 + self.load_on(knot.cell, pull) as f32 * ROPE_LOAD;
+            let velocity = Vector2{
+                x: (knot.position.x - knot.previous.x) * ROPE_DAMPING,
+                y: (knot.position.y - knot.previous.y) * ROPE_DAMPING,
+            };
+            knot.previous = knot.position;
+            knot.position.x += velocity.x + pull.x * weight;
+            knot.position.y += velocity.y + pull.y * weight;
+            self.knots.insert(*id, knot);
+        }
+
+        // anchored knots don't give, the others meet halfway
+        for _ in 0..ROPE_ITERATIONS {
+            for (a, b) in links.iter() {
+                let (knot_a, knot_b) = (self.knots[a], self.knots[b]);
+                let apart = Vector2{x: knot_b.position.x - knot_a.position.x, y: knot_b.position.y - knot_a.position.y};
+                let distance = (apart.x*apart.x + apart.y*apart.y).sqrt();
+                if distance <= ROPE_LINK {
+                    continue;
+                }
+                let share = match (knot_a.anchor.is_some(), knot_b.anchor.is_some()) {
+                    (true, true) => continue,
+                    (true, false) => 0.0,
+                    (false, true) => 1.0,
+                    (false, false) => 0.5,
+                };
+                let excess = (distance - ROPE_LINK) / distance;
+                let knot = self.knots.get_mut(a).unwrap();
+                knot.position.x += apart.x * excess * share;
+                knot.position.y += apart.y * excess * share;
+                let knot = self.knots.get_mut(b).unwrap();
+                knot.position.x -= apart.x * excess * (1.0 - share);
+                knot.position.y -= apart.y * excess * (1.0 - share);
+            }
+        }
+
+        for (a, b) in links {
+            let (knot_a, knot_b) = (self.knots[&a], self.knots[&b]);
+            let apart = Vector2{x: knot_b.position.x - knot_a.position.x, y: knot_b.position.y - knot_a.position.y};
+            let snap_length = self.materials.of(self.get(knot_a.cell.x, knot_a.cell.y))
+                .and_then(|material| material.rope.as_ref())
+                .map_or(f32::MAX, |rope| rope.snap_length);
+            if (apart.x*apart.x + apart.y*apart.y).sqrt() > snap_length {
+                self.grid.get_mut(knot_a.cell.x, knot_a.cell.y).particle_data_mut().unwrap().links.retain(|&link| link != b);
+                self.grid.get_mut(knot_b.cell.x, knot_b.cell.y).particle_data_mut().unwrap().links.retain(|&link| link != a);
+            }
+        }
+
+        // the edges of the world stop knots like walls do
+        let max = Vector2{x: self.width() as f32 - 1.0, y: self.height() as f32 - 1.0};
+        for knot in self.knots.values_mut() {
+            if !(0.0..=max.x).contains(&knot.position.x) {
+                knot.position.x = knot.position.x.clamp(0.0, max.x);
+                knot.previous.x = knot.position.x;
+            }
+            if !(0.0..=max.y).contains(&knot.position.y) {
+                knot.position.y = knot.position.y.clamp(0.0, max.y);
+                knot.previous.y = knot.position.y;
+            }
+        }
+        let target = |knot: &Knot| Vector2{x: knot.position.x.round() as usize, y: knot.position.y.round() as usize};
+        let mut moving : HashSet<u32> = self.knots.iter().filter(|(_, knot)| target(knot) != knot.cell).map(|(id, _)| *id).collect();
+        // the knots furthest along the pull go first, making way for the ones following them
+        let pull = self.gravity;
+        let depth = |knot: &Knot| knot.cell.x as f32 * pull.x + knot.cell.y as f32 * pull.y;
+        ids.sort_by(|a, b| depth(&self.knots[b]).total_cmp(&depth(&self.knots[a])));
+
+        for id in ids {
+            let mut knot = self.knots[&id];
+            let target = target(&knot);
+
+            // one cell at a time, sideways first, and never leaving a gap in the rope
+            let start = knot.cell;
+            let mover = self.get(knot.cell.x, knot.cell.y).clone();
+            let links = mover.particle_data().unwrap().links.clone();
+            let (mut stuck_x, mut stuck_y) = (false, false);
+            loop {
+                let next = if knot.cell.x != target.x && !stuck_x {
+                    Vector2{x: if target.x > knot.cell.x { knot.cell.x + 1 } else { knot.cell.x - 1 }, y: knot.cell.y}
+                } else if knot.cell.y != target.y && !stuck_y {
+                    Vector2{x: knot.cell.x, y: if target.y > knot.cell.y { knot.cell.y + 1 } else { knot.cell.y - 1 }}
+                } else {
+                    break;
+                };
+                let held = !self.keeps_links(&links, knot.cell, next, &moving);
+                if !held && self.passable(&mover, next.x, next.y) {
+                    self.move_cell(knot.cell, next, mover.clone());
+                    ret.push(knot.cell);
+                    ret.push(next);
+                    knot.cell = next;
+                    continue;
+                }
+                // its own rope only holds it back for now, anything else stops it dead
+                let stopped = !held && self.get(next.x, next.y).particle_data().map_or(true, |data| data.knot.is_none());
+                let sideways = next.y == knot.cell.y;
+                if sideways {
+                    stuck_x = true;
+                    if stopped {
+                        knot.position.x = knot.cell.x as f32;
+                        knot.previous.x = knot.position.x;
+                    }
+                } else {
+                    stuck_y = true;
+                    if stopped {
+                        knot.position.y = knot.cell.y as f32;
+                        knot.previous.y = knot.position.y;
+                    }
+                }
+            }
+
+            // knots piled on their own rope slide off it, so ropes slump instead of standing up
+            let down = heading(self.pull_at(knot.cell.x, knot.cell.y)).filter(|&down| {
+                knot.cell == start && target != start && self.offset(knot.cell.x, knot.cell.y, AROUND[down])
+                    .map_or(false, |below| self.get(below.x, below.y).particle_data().map_or(false, |data| data.knot.is_some()))
+            });
+            if let Some(down) = down {
+                let slides : Vec<Vector2<usize>> = [AROUND[(down + 7) % 8], AROUND[(down + 1) % 8]].iter()
+                    .filter_map(|&offset| self.offset(knot.cell.x, knot.cell.y, offset))
+                    .filter(|&slide| self.passable(&mover, slide.x, slide.y) && self.keeps_links(&links, knot.cell, slide, &HashSet::new()))
+                    .collect();
+                if let Some(&slide) = slides.choose(&mut self.rng) {
+                    self.move_cell(knot.cell, slide, mover.clone());
+                    ret.push(knot.cell);
+                    ret.push(slide);
+                    knot.cell = slide;
+                    knot.position = Vector2{x: slide.x as f32, y: slide.y as f32};
+                    knot.previous = knot.position;
+                }
+            }
+            moving.remove(&id);
+            self.knots.insert(id, knot);
+        }
+        ret
+    }
+
+    // Makes the rope particle at the position a knot, linked to the knots around it and tied to a static particle it touches
+    fn tie_knot(&mut self, at: Vector2<usize>) {
+        let cell = self.get(at.x, at.y);
+        if !self.materials.of(cell).map_or(false, |material| material.rope.is_some())
+            || cell.particle_data().map_or(true, |data| data.knot.is_some()) {
+            return;
+        }
+        let id = self.next_knot;
+        self.next_knot += 1;
+        let mut links = vec![];
+        let mut anchor = None;
+        for neighbor in self.neighbors(at.x, at.y) {
+            match self.get(neighbor.x, neighbor.y).particle_data().and_then(|data| data.knot) {
+                Some(other) if self.knots.contains_key(&other) => links.push(other),
+                _ => if anchor.is_none() && self.holds_ropes(self.get(neighbor.x, neighbor.y)) {
+                    anchor = Some(neighbor);
+                },
+            }
+        }
+        for other in links.iter() {
+            let cell = self.knots[other].cell;
+            self.grid.get_mut(cell.x, cell.y).particle_data_mut().unwrap().links.push(id);
+        }
+        let data = self.grid.get_mut(at.x, at.y).particle_data_mut().unwrap();
+        data.knot = Some(id);
+        data.links = links;
+        let position = Vector2{x: at.x as f32, y: at.y as f32};
+        self.knots.insert(id, Knot{position: position, previous: position, cell: at, anchor: anchor});
+    }
+
+    // Whether moving a knot between the cells keeps it next to the knots it's linked to, or at least no further from them.
+    // Knots still moving may be left a cell behind, as they're about to follow
+    fn keeps_links(&self, links: &[u32], from: Vector2<usize>, to: Vector2<usize>, moving: &HashSet<u32>) -> bool {
+        links.iter().filter(|link| self.knots.contains_key(link)).all(|link| {
+            let other = self.knots[link].cell;
+            let apart = |at: Vector2<usize>| at.x.abs_diff(other.x).max(at.y.abs_diff(other.y));
+            apart(to) <= apart(from).max(if moving.contains(link) { 2 } else { 1 })
+        })
+    }
+
+    // Whether ropes can hang from the particle: anything static, other than ropes and rigid bodies that may fall away
+    fn holds_ropes(&self, cell: &CellType) -> bool {
+        self.materials.of(cell).map_or(false, |material| {
+            material.movement == Movement::Static && material.rope.is_none() && material.rigid.is_none()
+        })
+    }
+
+    // How many powder or liquid particles are piled on the cell, against the pull, up to ROPE_MAX_LOAD
+    fn load_on(&self, at: Vector2<usize>, pull: Vector2<f32>) -> usize {
+        let up = match heading(pull) {
+            Some(down) => AROUND[(down + 4) % 8],
+            None => return 0,
+        };
+        (1..=ROPE_MAX_LOAD as i32).take_while(|&k| self.offset(at.x, at.y, (up.0 * k, up.1 * k)).map_or(false, |above| {
+            self.materials.of(self.get(above.x, above.y))
+                .map_or(false, |material| material.movement == Movement::Powder || material.movement == Movement::Liquid)
+        })).count()
+    }
+
+    // Moves a particle to another cell. Whatever was there (if anything) takes its place, each one carrying its own heat
+    fn move_cell(&mut self, from: Vector2<usize>, to: Vector2<usize>, moved: CellType) {
+        let displaced = self.get(to.x, to.y).clone();
+        self.grid.set(from.x, from.y, displaced);
+        self.grid.set(to.x, to.y, moved);
+        self.temperature.swap(from.x, from.y, to.x, to.y);
+    }
+
+    // Heat flows between neighbor cells of the room, as fast as the worse conductor of each pair allows.
+    // Rooms where heat is still flowing are kept awake
+    fn conduct_room(&mut self, room_x:usize, room_y:usize) {
+        let x_range = (room_x*self.room_size.x)..min(self.width(), (room_x+1)*self.room_size.x);
+        let y_range = (room_y*self.room_size.y)..min(self.height(), (room_y+1)*self.room_size.y);
+        for x in x_range {
+            for y in y_range.clone() {
+                let conductivity = self.conductivity(self.get(x, y));
+                for (nx, ny) in [(x+1, y), (x, y+1)] {
+                    if nx >= self.width() || ny >= self.height() {
+                        continue;
+                    }
+                    let k = conductivity.min(self.conductivity(self.get(nx, ny)));
+                    let flow = (self.temperature(nx, ny) - self.temperature(x, y)) * k / 4.0;
+                    *self.temperature.get_mut(x, y) += flow;
+                    *self.temperature.get_mut(nx, ny) -= flow;
+                    if flow.abs() > HEAT_FLOW_THRESHOLD {
+                        self.hot(x, y);
+                        self.hot(nx, ny);
+                    }
+                }
+                let cell = self.get(x, y);
+                if *cell == CellType::Empty || self.materials.of(cell).map_or(false, |material| material.movement == Movement::Gas) {
+                    let temperature = self.temperature.get_mut(x, y);
+                    *temperature += (AMBIENT_TEMPERATURE - *temperature) * AIR_COOLING;
+                }
+            }
+        }
+    }
+
+    // Pressure piles up along liquid columns and spreads sideways and up through connected liquid, a bit more every frame.
+    fn pressurize_room(&mut self, room_x:usize, room_y:usize) {
+        let x_range = (room_x*self.room_size.x)..min(self.width(), (room_x+1)*self.room_size.x);
+        let y_range = (room_y*self.room_size.y)..min(self.height(), (room_y+1)*self.room_size.y);
+        for x in x_range {
+            // how far below the actual surface of its column the cell is, so pressure can't hold itself up once the liquid is gone
+            let mut depth = None;
+            for y in y_range.clone() {
+                let material = match self.get(x, y).material().filter(|_| self.is_liquid(self.get(x, y))) {
+                    Some(material) => material,
+                    None => {
+                        self.pressure.set(x, y, 0.0);
+                        depth = Some(0.0);
+                        continue;
+                    },
+                };
+                let same = |nx: usize, ny: usize| self.get(nx, ny).material() == Some(material);
+                let column = match depth {
+                    Some(depth) if y > 0 && same(x, y-1) => depth + 1.0,
+                    Some(_) => 0.0,
+                    None => (1..=y).take_while(|&d| same(x, y-d)).count() as f32,
+                };
+                depth = Some(column);
+                // pressure only spreads within the same liquid, and up from below to the surfaces it presses on
+                let mut pressure = column;
+                if y+1 < self.height() && same(x, y+1) {
+                    pressure = pressure.max(self.pressure.get(x, y+1) - 1.0);
+                }
+                for nx in [x.wrapping_sub(1), x+1] {
+                    if nx < self.width() && same(nx, y) {
+                        pressure = pressure.max(self.pressure.get(nx, y) - PRESSURE_SIDE_LOSS);
+                    }
+                }
+                // rooms stay awake while pressure is still spreading, or pressing on a surface
+                let pressed = pressure >= PRESSURE_THRESHOLD && y > 0 && *self.get(x, y-1) == CellType::Empty;
+                if pressed || (pressure - self.pressure.get(x, y)).abs() > 0.5 {
+                    self.hot(x, y);
+                }
+                self.pressure.set(x, y, pressure);
+            }
+        }
+    }
+
+    // The highest liquid surface of the body the liquid cell at (x,y) belongs to, which is what presses on it.
+    // Other liquids touching it aren't part of its body
+    fn pressure_source(&self, x: usize, y: usize) -> Option<Vector2<usize>> {
+        let material = self.get(x, y).material();
+        let same = |at: Vector2<usize>| self.get(at.x, at.y).material() == material;
+        let mut ret : Option<Vector2<usize>> = None;
+        let mut visited = HashSet::new();
+        let mut pending = vec![Vector2{x: x, y: y}];
+        visited.insert(Vector2{x: x, y: y});
+        // huge bodies are not worth searching through every frame
+        while let Some(current) = pending.pop() {
+            if visited.len() > MAX_PRESSURE_SEARCH {
+                break;
+            }
+            let is_surface = current.y == 0 || !same(Vector2{x: current.x, y: current.y-1});
+            if is_surface && ret.map_or(true, |highest| current.y < highest.y) {
+                ret = Some(current);
+            }
+            let mut neighbors = vec![];
+            if current.y > 0 {
+                neighbors.push(Vector2{x: current.x, y: current.y-1});
+            }
+            if current.y+1 < self.height() {
+                neighbors.push(Vector2{x: current.x, y: current.y+1});
+            }
+            if current.x > 0 {
+                neighbors.push(Vector2{x: current.x-1, y: current.y});
+            }
+            if current.x+1 < self.width() {
+                neighbors.push(Vector2{x: current.x+1, y: current.y});
+            }
+            for neighbor in neighbors {
+                if same(neighbor) && visited.insert(neighbor) {
+                    pending.push(neighbor);
+                }
+            }
+        }
+        ret
+    }
+
+    // Where a gas particle at (x,y) drifts to: along the wind if it blows hard enough, else against its pull, randomly to the sides.
+    // Weightless gases just wander around
+    fn rise(&mut self, x: usize, y: usize, drift: Vector2<f32>, dirty_cells: &HashSet<Vector2<usize>>) -> Vector2<usize> {
+        let mut candidates = vec![];
+        let (wind_dx, wind_dy) = (drift.x.round().clamp(-1.0, 1.0) as i32, drift.y.round().clamp(-1.0, 1.0) as i32);
+        match heading(self.pull_at(x, y)) {
+            Some(down) => {
+                let turn = |turns: i32| AROUND[(down as i32 + 4 + turns).rem_euclid(8) as usize];
+                let dx = self.gen_range(-1..=1);
+                if wind_dx != 0 || wind_dy != 0 {
+                    let (up_x, up_y) = turn(0);
+                    candidates.push((wind_dx, wind_dy));
+                    candidates.push(((wind_dx + up_x).clamp(-1, 1), (wind_dy + up_y).clamp(-1, 1)));
+                }
+                candidates.extend([turn(dx), turn(-dx)]);
+                if dx != 0 {
+                    candidates.push(turn(2*dx));
+                }
+            },
+            None => {
+                if wind_dx != 0 || wind_dy != 0 {
+                    candidates.push((wind_dx, wind_dy));
+                }
+                candidates.push(AROUND[self.gen_range(0..=7)]);
+            },
+        }
+        for offset in candidates {
+            if let Some(candidate) = self.offset(x, y, offset) {
+                if *self.get(candidate.x, candidate.y) == CellType::Empty
+                    && !dirty_cells.contains(&candidate) {
+                    return candidate;
+                }
+            }
+        }
+        Vector2{x: x, y: y}
+    }
+
+    // Air carries its own velocity along, spreading it to neighbor rooms as it calms down
+    fn advect_wind(&mut self) {
+        let old = self.wind.clone();
+        let width = old.width();
+        let height = old.height();
+        let at = |x: i32, y: i32| *old.get(x.clamp(0, width as i32 - 1) as usize, y.clamp(0, height as i32 - 1) as usize);
+        for room_x in 0..width {
+            for room_y in 0..height {
+                let velocity = *old.get(room_x, room_y);
+                // the air now over this room comes from upwind
+                let from_x = room_x as f32 - velocity.x / self.room_size.x as f32;
+                let from_y = room_y as f32 - velocity.y / self.room_size.y as f32;
+                let (x0, y0) = (from_x.floor() as i32, from_y.floor() as i32);
+                let (tx, ty) = (from_x - x0 as f32, from_y - y0 as f32);
+                let lerp = |a: Vector2<f32>, b: Vector2<f32>, t: f32| Vector2{x: a.x + (b.x - a.x) * t, y: a.y + (b.y - a.y) * t};
+                let advected = lerp(
+                    lerp(at(x0, y0), at(x0+1, y0), tx),
+                    lerp(at(x0, y0+1), at(x0+1, y0+1), tx),
+                    ty,
+                );
+                let (room_x, room_y) = (room_x as i32, room_y as i32);
+                let neighbors = [at(room_x-1, room_y), at(room_x+1, room_y), at(room_x, room_y-1), at(room_x, room_y+1)];
+                let average = Vector2{
+                    x: neighbors.iter().map(|v| v.x).sum::<f32>() / 4.0,
+                    y: neighbors.iter().map(|v| v.y).sum::<f32>() / 4.0,
+                };
+                let mut new_velocity = lerp(advected, average, WIND_DIFFUSION);
+                new_velocity.scale(WIND_DAMPING);
+                if new_velocity.x.abs() > WIND_CALM || new_velocity.y.abs() > WIND_CALM {
+                    self.grid_rooms_hotness.set(room_x as usize, room_y as usize, 12);
+                }
+                self.wind.set(room_x as usize, room_y as usize, new_velocity);
+            }
+        }
+    }
+
+    fn conductivity(&self, cell: &CellType) -> f32 {
+        self.materials.of(cell).map_or(AIR_CONDUCTIVITY, |material| material.conductivity)
+    }
+
+    fn density(&self, cell: &CellType) -> f32 {
+        self.materials.of(cell).map_or(0.0, |material| {
+            // dissolved solute weighs its solvent down
+            let solute = material.solution.as_ref().map_or(0.0, |solution| solution.density * cell.particle_data().unwrap().concentration);
+            material.density + solute
+        })
+    }
+
+    fn is_liquid(&self, cell: &CellType) -> bool {
+        self.materials.of(cell).map_or(false, |material| material.movement == Movement::Liquid)
+    }
+
+    // Whether other particles can push this one around
+    fn is_movable(&self, cell: &CellType) -> bool {
+        self.materials.of(cell).map_or(false, |material| material.movement != Movement::Static)
+    }
+
+    // Whether the mover particle can go into (x,y), either because it is empty or by displacing a lighter particle
+    fn passable(&self, mover: &CellType, x: usize, y: usize) -> bool {
+        let target = self.get(x, y);
+        *target == CellType::Empty || (self.is_movable(target) && self.density(target) < self.density(mover))
+    }
+
+    // Whether any of the 8 neighbors of (x,y) matches the predicate
+    fn touches<F>(&self, x: usize, y: usize, predicate: F) -> bool
+    where F : Fn(&CellType) -> bool {
+        self.neighbors(x, y).iter().any(|at| predicate(self.get(at.x, at.y)))
+    }
+
+    // Flames at (x,y) may set flammable neighbors on fire. Returns the cells that caught fire
+    fn ignite_neighbors(&mut self, x: usize, y: usize) -> Vec<Vector2<usize>> {
+        let mut ret = vec![];
+        let materials = self.materials.clone();
+        for neighbor in self.neighbors(x, y) {
+            let cell = self.get(neighbor.x, neighbor.y);
+            let material = match materials.of(cell) {
+                Some(material) if material.flammability > 0.0 && !cell.is_burning() => material,
+                _ => continue,
+            };
+            if !self.gen_bool(material.flammability) {
+                continue;
+            }
+            let burn_ticks = self.gen_range(material.burn_ticks.0..=material.burn_ticks.1);
+            if let Some(data) = self.grid.get_mut(neighbor.x, neighbor.y).particle_data_mut() {
+                data.burning = Some(burn_ticks);
+                ret.push(neighbor);
+            }
+        }
+        ret
+    }
+
+    // Burns the particle at (x,y) for one more tick, spitting flames and spreading to its neighbors.
+    // Returns the changed cells and whether the particle got consumed
+    fn burn(&mut self, x: usize, y: usize, dirty_cells: &mut HashSet<Vector2<usize>>) -> (Vec<Vector2<usize>>, bool) {
+        let mut ret = vec![Vector2{x: x, y: y}];
+        let materials = self.materials.clone();
+        let flame = materials.flame();
+        let ticks_left = self.get(x, y).particle_data().and_then(|data| data.burning).unwrap_or(0);
+        if ticks_left <= 1 {
+            let leftover = match materials.of(self.get(x, y)) {
+                Some(material) => self.random_outcome(&material.burns_into),
+                None => CellType::Empty,
+            };
+            self.set(x, y, leftover);
+            dirty_cells.insert(Vector2{x: x, y: y});
+            return (ret, true);
+        }
+
+        let color = flame.map(|flame| self.random_color(flame));
+        if let Some(data) = self.grid.get_mut(x, y).particle_data_mut() {
+            data.burning = Some(ticks_left - 1);
+            if let Some(color) = color {
+                data.color = color;
+            }
+        }
+        let temperature = self.temperature.get_mut(x, y);
+        *temperature = temperature.max(BURNING_TEMPERATURE);
+
+        // flames spring up against the pull, or anywhere when weightless
+        if let Some(flame) = flame {
+            let up = match heading(self.pull_at(x, y)) {
+                Some(down) => AROUND[(down + 4) % 8],
+                None => AROUND[self.gen_range(0..=7)],
+            };
+            match self.offset(x, y, up) {
+                Some(at) if *self.get(at.x, at.y) == CellType::Empty && self.gen_bool(0.3) => {
+                    let flame = self.new_particle(flame);
+                    self.set(at.x, at.y, flame);
+                    dirty_cells.insert(at);
+                    ret.push(at);
+                },
+                _ => {},
+            }
+        }
+
+        ret.extend(self.ignite_neighbors(x, y));
+        (ret, false)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world(width: usize, height: usize) -> World {
+        World::new(width, height, Rc::new(MaterialRegistry::builtin()))
+    }
+
+    fn place(world: &mut World, x: usize, y: usize, name: &str) {
+        let particle = world.new_particle(world.materials.id(name).unwrap());
+        world.set(x, y, particle);
+    }
+
+    fn is(world: &World, x: usize, y: usize, name: &str) -> bool {
+        world.materials.of(world.get(x, y)).map_or(false, |material| material.name == name)
+    }
+
+    fn count(world: &World, name: &str) -> usize {
+        (0..world.width()).flat_map(|x| (0..world.height()).map(move |y| (x, y))).filter(|&(x, y)| is(world, x, y, name)).count()
+    }
+
+    #[test]
+    fn u_tube_levels_even_out() {
+        let mut world = world(30, 40);
+        // walls of a U with 3 cells wide arms, joined at the bottom
+        for y in 10..39 {
+            place(&mut world, 4, y, "block");
+            place(&mut world, 23, y, "block");
+        }
+        for y in 10..34 {
+            place(&mut world, 8, y, "block");
+            place(&mut world, 19, y, "block");
+        }
+        for x in 4..24 {
+            place(&mut world, x, 38, "block");
+        }
+        for x in 5..8 {
+            for y in 12..34 {
+                place(&mut world, x, y, "water");
+            }
+        }
+        for x in 5..23 {
+            for y in 34..38 {
+                place(&mut world, x, y, "water");
+            }
+        }
+        let water = count(&world, "water");
+        for _ in 0..600 {
+            world.process_frame();
+        }
+        let level = |arm: std::ops::Range<usize>| (0..38).find(|&y| arm.clone().any(|x| is(&world, x, y, "water"))).unwrap();
+        assert_eq!(count(&world, "water"), water);
+        assert!(level(5..8).abs_diff(level(20..23)) <= 2, "arms at {} and {}", level(5..8), level(20..23));
+    }
+
+    #[test]
+    fn pulse_moves_a_cell_per_tick() {
+        // both ways, so rooms get processed before and after the ones the pulse comes from
+        for battery in [0, 95] {
+            let mut world = world(96, 8);
+            for x in 1..95 {
+                place(&mut world, x, 4, "wire");
+            }
+            place(&mut world, battery, 4, "battery");
+            let mut reached = vec![None; 96];
+            for tick in 0..120 {
+                world.process_frame();
+                for x in 1..95 {
+                    if reached[x].is_none() && world.get(x, 4).particle_data().unwrap().charge > 0 {
+                        reached[x] = Some(tick);
+                    }
+                }
+            }
+            let reached : Vec<i32> = reached[1..95].iter().map(|tick| tick.expect("the pulse didn't get through")).collect();
+            assert!(reached.windows(2).all(|pair| (pair[1] - pair[0]).abs() == 1), "{:?}", reached);
+        }
+    }
+
+    #[test]
+    fn salt_crystallizes_out_of_boiling_water() {
+        let mut world = world(30, 30);
+        for x in 4..16 {
+            place(&mut world, x, 25, "block");
+        }
+        for y in 15..25 {
+            place(&mut world, 4, y, "block");
+            place(&mut world, 15, y, "block");
+        }
+        for x in 5..15 {
+            for y in 21..25 {
+                place(&mut world, x, y, "water");
+                world.grid.get_mut(x, y).particle_data_mut().unwrap().concentration = 0.5;
+            }
+        }
+        // in grains of salt
+        let dissolved = |world: &World| (0..world.width()).flat_map(|x| (0..world.height()).map(move |y| (x, y)))
+            .filter(|&(x, y)| is(world, x, y, "water"))
+            .map(|(x, y)| world.get(x, y).particle_data().unwrap().concentration / 0.25)
+            .sum::<f32>();
+        assert!((dissolved(&world) - 80.0).abs() < 0.01);
+        // hot enough all around that the steam doesn't condense back
+        for _ in 0..300 {
+            for x in 0..30 {
+                for y in 0..25 {
+                    world.temperature.set(x, y, 150.0);
+                }
+            }
+            world.process_frame();
+        }
+        assert!(dissolved(&world) < 0.01);
+        // a fraction of a grain crystallizes by chance
+        assert!(count(&world, "salt").abs_diff(80) <= 4, "{} grains of salt", count(&world, "salt"));
+    }
+
+    #[test]
+    fn flames_spring_up_against_the_pull() {
+        for (gravity, up) in [((0.15, 0.0), (4, 5)), ((-0.15, 0.0), (6, 5)), ((0.0, -0.15), (5, 6))] {
+            let mut world = world(10, 10);
+            world.set_gravity(Vector2{x: gravity.0, y: gravity.1});
+            place(&mut world, 5, 5, "wood");
+            world.grid.get_mut(5, 5).particle_data_mut().unwrap().burning = Some(1000);
+            for _ in 0..100 {
+                world.burn(5, 5, &mut HashSet::new());
+            }
+            assert!(is(&world, up.0, up.1, "fire"));
+            assert_eq!(count(&world, "fire"), 1);
+        }
+    }
+
+    #[test]
+    fn plants_grow_against_the_pull() {
+        let mut world = world(10, 10);
+        world.set_gravity(Vector2{x: 0.15, y: 0.0});
+        place(&mut world, 5, 5, "plant");
+        place(&mut world, 6, 5, "block");
+        world.grid.get_mut(5, 5).particle_data_mut().unwrap().energy = 5;
+        let mut grown = vec![];
+        while grown.is_empty() {
+            grown = world.grow(5, 5, &mut HashSet::new());
+        }
+        assert!(grown.iter().all(|at| at.x == 4), "{:?}", grown.iter().map(|at| (at.x, at.y)).collect::<Vec<_>>());
+
+        // resting on something below it, but not along the pull, a seed hasn't landed
+        place(&mut world, 2, 2, "seed");
+        place(&mut world, 2, 3, "block");
+        place(&mut world, 1, 2, "water");
+        for _ in 0..1000 {
+            world.grow(2, 2, &mut HashSet::new());
+        }
+        assert!(is(&world, 2, 2, "seed") && is(&world, 1, 2, "water"));
+    }
 }